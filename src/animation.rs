@@ -0,0 +1,256 @@
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use backend::Backend;
+use bspwm;
+use config::Easing;
+use layout;
+
+/**
+  Time between the steps of an animation, about 60 steps per second
+*/
+pub const FRAME_MS: u64 = 16;
+
+/**
+  Returns how far along an animation is at `progress` between 0 and 1
+*/
+pub fn ease(easing: &Easing, progress: f64) -> f64
+{
+    let t = progress.max(0.).min(1.);
+
+    match *easing
+    {
+        Easing::Linear => t,
+        Easing::EaseOut => 1. - (1. - t) * (1. - t),
+        Easing::EaseInOut => match t < 0.5
+        {
+            true => 2. * t * t,
+            false => 1. - 2. * (1. - t) * (1. - t)
+        }
+    }
+}
+
+/**
+  Splits the change from the `from` ratios to the `to` ratios into the
+  ratios of each step. The last step always ends at the `to` ratios
+*/
+pub fn ratio_frames(from: &Vec<(u64, f64)>, to: &Vec<(u64, f32)>, easing: &Easing, duration_ms: u64)
+    -> Vec<Vec<(u64, f32)>>
+{
+    let steps = ::std::cmp::max(1, duration_ms / FRAME_MS);
+
+    (1..steps + 1)
+        .map(|step| {
+            let progress = ease(easing, step as f64 / steps as f64);
+
+            to.iter()
+                .map(|&(node, target)| {
+                    let start = from.iter()
+                        .find(|&&(id, _)| id == node)
+                        .map(|&(_, ratio)| ratio)
+                        .unwrap_or(target as f64);
+
+                    match step == steps
+                    {
+                        true => (node, target),
+                        false => (node, (start + (target as f64 - start) * progress) as f32)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/**
+  The running animations as (stack root, animation id). An animation stops
+  as soon as it is no longer in the list. Clones share the same list
+*/
+#[derive(Clone, Default)]
+pub struct Animations
+{
+    running: Arc<Mutex<Vec<(u64, usize)>>>,
+    next_id: Arc<AtomicUsize>
+}
+
+impl Animations
+{
+    pub fn new() -> Animations
+    {
+        Animations {
+            running: Arc::new(Mutex::new(vec!())),
+            next_id: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    /**
+      Whether an animation of the stack is running
+    */
+    pub fn is_running(&self, root: u64) -> bool
+    {
+        self.running.lock().unwrap().iter().any(|&(running_root, _)| running_root == root)
+    }
+
+    /**
+      Stops the animation of the stack if one is running
+    */
+    pub fn cancel(&self, root: u64)
+    {
+        self.running.lock().unwrap().retain(|&(running_root, _)| running_root != root);
+    }
+
+    /**
+      Plays the frames on a separate thread, replacing the animation that is
+      running for the stack. The lock on the list is not held while a step
+      is sent, so a step can still land after the animation was cancelled.
+      It is overwritten by the layout that cancelled the animation.
+
+      If bspwm fails, all nodes of the failed step get their `previous`
+      ratios back and the `changed_flags` that were set for the animation
+      are flipped back
+    */
+    pub fn start(
+            &self,
+            bspc: Box<dyn Backend + Send>,
+            root: u64,
+            frames: Vec<Vec<(u64, f32)>>,
+            previous: Vec<(u64, f64)>,
+            changed_flags: Vec<(u64, bool)>
+        )
+    {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+
+        {
+            let mut running = self.running.lock().unwrap();
+            running.retain(|&(running_root, _)| running_root != root);
+            running.push((root, id));
+        }
+
+        let running = self.running.clone();
+        thread::spawn(move || {
+            for frame in frames
+            {
+                if !running.lock().unwrap().contains(&(root, id))
+                {
+                    return
+                }
+
+                let sent = frame.iter()
+                    .map(|&(node, ratio)| bspwm::node_change_ratio(&*bspc, &bspwm::get_node_name(node), ratio))
+                    .collect::<Result<Vec<_>, _>>();
+
+                if let Err(e) = sent
+                {
+                    println!("Animation failed: {}", e);
+                    bspwm::restore_ratios(&*bspc, &frame, &previous);
+                    layout::restore_hidden(&*bspc, &changed_flags);
+                    running.lock().unwrap().retain(|&entry| entry != (root, id));
+                    return
+                }
+
+                thread::sleep(Duration::from_millis(FRAME_MS));
+            }
+
+            running.lock().unwrap().retain(|&entry| entry != (root, id));
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod animation_tests
+{
+    use super::*;
+
+    use backend::MockBackend;
+
+    #[test]
+    fn ease_test()
+    {
+        for easing in vec!(Easing::Linear, Easing::EaseOut, Easing::EaseInOut)
+        {
+            assert_eq!(ease(&easing, 0.), 0.);
+            assert_eq!(ease(&easing, 1.), 1.);
+        }
+
+        assert_eq!(ease(&Easing::Linear, 0.25), 0.25);
+        assert_eq!(ease(&Easing::EaseOut, 0.5), 0.75);
+        assert_eq!(ease(&Easing::EaseInOut, 0.25), 0.125);
+    }
+
+    #[test]
+    fn ratio_frames_test()
+    {
+        let from = vec!((1, 0.5), (2, 0.25));
+        let to = vec!((1, 0.75), (2, 0.25), (3, 0.5));
+
+        assert_eq!(ratio_frames(&from, &to, &Easing::Linear, 4 * FRAME_MS), vec!(
+                vec!((1, 0.5625), (2, 0.25), (3, 0.5)),
+                vec!((1, 0.625), (2, 0.25), (3, 0.5)),
+                vec!((1, 0.6875), (2, 0.25), (3, 0.5)),
+                vec!((1, 0.75), (2, 0.25), (3, 0.5)),
+            ));
+
+        //Short animations still end up at the target
+        assert_eq!(ratio_frames(&from, &to, &Easing::EaseOut, 1), vec!(to.clone()));
+    }
+
+    #[test]
+    fn replace_animation_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let animations = Animations::new();
+        let root = 4194640;
+
+        let slow = ::std::iter::repeat(vec!((root, 0.25))).take(20)
+            .chain(::std::iter::once(vec!((root, 0.125))))
+            .collect();
+        animations.start(bspc.connect(), root, slow, vec!(), vec!());
+        animations.start(bspc.connect(), root, vec!(vec!((root, 0.75))), vec!(), vec!());
+
+        //The first animation is cancelled before its final frame
+        while animations.is_running(root)
+        {
+            thread::sleep(Duration::from_millis(FRAME_MS));
+        }
+        thread::sleep(Duration::from_millis(2 * FRAME_MS));
+
+        let commands = bspc.commands();
+        assert_eq!(commands.last(), Some(&"node 0x400150 -r 0.75".to_string()));
+        assert!(!commands.contains(&"node 0x400150 -r 0.125".to_string()));
+    }
+
+    #[test]
+    fn failed_animation_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let animations = Animations::new();
+
+        let previous = vec!((4194640, 0.5), (4194636, 0.25));
+        let frames = vec!(
+                vec!((4194640, 0.625), (4194636, 0.375)),
+                vec!((4194640, 0.75), (4194636, 0.5)),
+                vec!((4194640, 0.875), (4194636, 0.625)),
+            );
+
+        //The second node of the second step fails
+        bspc.fail_commands("0x40014C -r 0.5");
+        animations.start(bspc.connect(), 4194640, frames, previous, vec!((29541363, true)));
+
+        while animations.is_running(4194640)
+        {
+            thread::sleep(Duration::from_millis(FRAME_MS));
+        }
+
+        //Every node is back at its ratio from before the animation
+        let last_command = |prefix: &str| {
+            bspc.commands().into_iter().rev().find(|command| command.starts_with(prefix))
+        };
+        assert_eq!(last_command("node 0x400150 -r"), Some("node 0x400150 -r 0.5".to_string()));
+        assert_eq!(last_command("node 0x40014C -r"), Some("node 0x40014C -r 0.25".to_string()));
+        assert_eq!(last_command("node 0x1C2C3F3"), Some("node 0x1C2C3F3 -g hidden=off".to_string()));
+        assert!(!bspc.commands().contains(&"node 0x400150 -r 0.875".to_string()));
+    }
+}
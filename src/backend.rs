@@ -0,0 +1,1220 @@
+use rustc_serialize::json;
+
+use std::cell::{Cell, RefCell};
+use std::string::String;
+use std::env;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process;
+
+use subprogram::call_program;
+use bspwm::{self, BspwmError};
+
+/**
+  Something that can run bspwm queries, node and desktop commands and read
+  settings. The arguments are the same as the ones that would follow
+  `bspc query`, `bspc node`, `bspc desktop` and `bspc config` respectively.
+
+  Returns the output of the command if successfull
+*/
+pub trait Backend
+{
+    fn query(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>;
+
+    fn node(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>;
+
+    fn desktop(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>;
+
+    fn config(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>;
+
+    /**
+      Subscribes to the specified events. The returned reader produces
+      one line per event until bspwm goes away
+    */
+    fn subscribe(&self, events: &Vec<&str>) -> Result<Box<dyn BufRead>, BspwmError>;
+
+    /**
+      Returns a backend talking to the same bspwm which can be moved to
+      another thread
+    */
+    fn connect(&self) -> Box<dyn Backend + Send>;
+}
+
+
+/**
+  Backend that talks to bspwm by running the `bspc` program
+*/
+pub struct BspcProgram;
+
+impl Backend for BspcProgram
+{
+    fn query(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        let mut full_arguments = vec!("query");
+        full_arguments.extend(arguments.iter().cloned());
+
+        call_program("bspc", &full_arguments)
+    }
+
+    fn node(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        let mut full_arguments = vec!("node");
+        full_arguments.extend(arguments.iter().cloned());
+
+        call_program("bspc", &full_arguments)
+    }
+
+    fn desktop(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        let mut full_arguments = vec!("desktop");
+        full_arguments.extend(arguments.iter().cloned());
+
+        call_program("bspc", &full_arguments)
+    }
+
+    fn config(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        let mut full_arguments = vec!("config");
+        full_arguments.extend(arguments.iter().cloned());
+
+        call_program("bspc", &full_arguments)
+    }
+
+    fn subscribe(&self, events: &Vec<&str>) -> Result<Box<dyn BufRead>, BspwmError>
+    {
+        let output = ChildOutput::spawn(process::Command::new("bspc").arg("subscribe").args(events))
+            .map_err(|e| BspwmError::Spawn(format!("Failed to run bspc subscribe: {}", e)))?;
+
+        Ok(Box::new(output))
+    }
+
+    fn connect(&self) -> Box<dyn Backend + Send>
+    {
+        Box::new(BspcProgram)
+    }
+}
+
+/**
+  The output of a program that is still running. The program is stopped and
+  waited for when the output is dropped, so that it does not linger as a zombie
+*/
+struct ChildOutput
+{
+    child: process::Child,
+    output: BufReader<process::ChildStdout>
+}
+
+impl ChildOutput
+{
+    fn spawn(command: &mut process::Command) -> io::Result<ChildOutput>
+    {
+        let mut child = command.stdout(process::Stdio::piped()).spawn()?;
+        let output = BufReader::new(child.stdout.take().unwrap());
+
+        Ok(ChildOutput {
+            child: child,
+            output: output
+        })
+    }
+}
+
+impl Read for ChildOutput
+{
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize>
+    {
+        self.output.read(buffer)
+    }
+}
+
+impl BufRead for ChildOutput
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]>
+    {
+        self.output.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize)
+    {
+        self.output.consume(amount)
+    }
+}
+
+impl Drop for ChildOutput
+{
+    fn drop(&mut self)
+    {
+        //Killing fails if the program already exited, it is waited for either way
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+
+
+/**
+  Marker that bspwm puts in front of the reply if a message failed
+*/
+const FAILURE_MESSAGE: u8 = 0x07;
+
+/**
+  Backend that talks to bspwm directly over its UNIX socket, which avoids
+  forking bspc for every query
+*/
+pub struct BspwmSocket
+{
+    path: PathBuf
+}
+
+impl BspwmSocket
+{
+    pub fn new(path: PathBuf) -> BspwmSocket
+    {
+        BspwmSocket {
+            path: path
+        }
+    }
+
+    /**
+      Creates a socket backend using the same socket as bspc would. None if
+      neither BSPWM_SOCKET nor DISPLAY are set to something useful
+    */
+    pub fn from_environment() -> Option<BspwmSocket>
+    {
+        match env::var("BSPWM_SOCKET")
+        {
+            Ok(path) => Some(BspwmSocket::new(PathBuf::from(path))),
+            Err(_) =>
+            {
+                env::var("DISPLAY").ok()
+                    .and_then(|display| default_socket_path(&display))
+                    .map(BspwmSocket::new)
+            }
+        }
+    }
+
+    /**
+      Sends a message to bspwm and reads the reply. Every argument is
+      terminated by a NUL byte
+    */
+    fn connect_send(&self, arguments: &Vec<&str>) -> Result<UnixStream, BspwmError>
+    {
+        let mut stream = UnixStream::connect(&self.path)
+            .map_err(|e| BspwmError::Spawn(format!("Failed to connect to {}: {}", self.path.display(), e)))?;
+
+        let mut message = vec!();
+        for argument in arguments
+        {
+            message.extend_from_slice(argument.as_bytes());
+            message.push(0);
+        }
+
+        stream.write_all(&message)
+            .map_err(|e| BspwmError::Spawn(format!("Failed to send message to bspwm: {}", e)))?;
+
+        Ok(stream)
+    }
+
+    fn send_message(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        let mut stream = self.connect_send(arguments)?;
+
+        let mut reply = vec!();
+        stream.read_to_end(&mut reply)
+            .map_err(|e| BspwmError::Spawn(format!("Failed to read reply from bspwm: {}", e)))?;
+
+        if reply.first() == Some(&FAILURE_MESSAGE)
+        {
+            Err(BspwmError::NonZeroExit(String::from_utf8_lossy(&reply[1..]).trim().to_string()))
+        }
+        else
+        {
+            String::from_utf8(reply)
+                .map_err(|_| BspwmError::Unparsable("bspwm replied with invalid UTF8".to_string()))
+        }
+    }
+}
+
+impl Backend for BspwmSocket
+{
+    fn query(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        let mut full_arguments = vec!("query");
+        full_arguments.extend(arguments.iter().cloned());
+
+        self.send_message(&full_arguments)
+    }
+
+    fn node(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        let mut full_arguments = vec!("node");
+        full_arguments.extend(arguments.iter().cloned());
+
+        self.send_message(&full_arguments)
+    }
+
+    fn desktop(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        let mut full_arguments = vec!("desktop");
+        full_arguments.extend(arguments.iter().cloned());
+
+        self.send_message(&full_arguments)
+    }
+
+    fn config(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        let mut full_arguments = vec!("config");
+        full_arguments.extend(arguments.iter().cloned());
+
+        self.send_message(&full_arguments)
+    }
+
+    fn subscribe(&self, events: &Vec<&str>) -> Result<Box<dyn BufRead>, BspwmError>
+    {
+        let mut full_arguments = vec!("subscribe");
+        full_arguments.extend(events.iter().cloned());
+
+        //bspwm keeps the connection open and writes a line for every event
+        let stream = self.connect_send(&full_arguments)?;
+
+        Ok(Box::new(BufReader::new(stream)))
+    }
+
+    fn connect(&self) -> Box<dyn Backend + Send>
+    {
+        Box::new(BspwmSocket::new(self.path.clone()))
+    }
+}
+
+/**
+  Returns the path that bspwm uses for its socket when BSPWM_SOCKET is not set,
+  `/tmp/bspwm<host>_<display>_<screen>-socket`
+*/
+pub fn default_socket_path(display: &str) -> Option<PathBuf>
+{
+    let separator = match display.rfind(':')
+    {
+        Some(index) => index,
+        None => return None
+    };
+
+    let host = &display[..separator];
+    let mut numbers = display[separator + 1..].splitn(2, '.');
+
+    let display_number = match numbers.next().map(|n| n.parse::<u32>())
+    {
+        Some(Ok(number)) => number,
+        _ => return None
+    };
+    let screen_number = match numbers.next().map(|n| n.parse::<u32>())
+    {
+        None => 0,
+        Some(Ok(number)) => number,
+        Some(Err(_)) => return None
+    };
+
+    Some(PathBuf::from(format!("/tmp/bspwm{}_{}_{}-socket", host, display_number, screen_number)))
+}
+
+/**
+  Returns the backend that should be used to talk to bspwm. The socket is
+  preferred, bspc is used if the socket path can't be found
+*/
+pub fn default_backend() -> Box<dyn Backend>
+{
+    match BspwmSocket::from_environment()
+    {
+        Some(socket) => Box::new(socket),
+        None => {
+            println!("Could not find the bspwm socket, falling back to bspc");
+            Box::new(BspcProgram)
+        }
+    }
+}
+
+
+
+////////////////////////////////////////////////////////////////////////////////
+//                          Tree snapshots
+////////////////////////////////////////////////////////////////////////////////
+
+/**
+  Backend that wraps another one for the duration of a single daemon command.
+
+  The trees of all monitors are fetched once with `bspc query -T -m` and the
+  queries about specific nodes are answered from them, other queries are
+  passed on. Ratio, hidden flag and focus changes are applied to the
+  snapshot. Ratio and hidden flag changes move other nodes, so the next
+  query for a tree fetches the trees again. Any other command makes the
+  next query fetch a new snapshot. Settings are read once and the last node
+  that was focused by id is remembered.
+
+  Every call that reaches the wrapped backend is counted
+*/
+pub struct Snapshot<'a>
+{
+    inner: &'a dyn Backend,
+    monitors: RefCell<Option<Vec<json::Json>>>,
+    /// Whether the rectangles in the trees are out of date
+    dirty: Cell<bool>,
+    settings: RefCell<Vec<(Vec<String>, String)>>,
+    focused: Cell<Option<u64>>,
+    calls: Cell<usize>
+}
+
+impl<'a> Snapshot<'a>
+{
+    pub fn new(inner: &'a dyn Backend) -> Snapshot<'a>
+    {
+        Snapshot {
+            inner: inner,
+            monitors: RefCell::new(None),
+            dirty: Cell::new(false),
+            settings: RefCell::new(vec!()),
+            focused: Cell::new(None),
+            calls: Cell::new(0)
+        }
+    }
+
+    /**
+      The number of calls that were passed on to the wrapped backend
+    */
+    pub fn calls(&self) -> usize
+    {
+        self.calls.get()
+    }
+
+    /**
+      The last node that was focused through the snapshot with `node -f <id>`
+    */
+    pub fn focused_node(&self) -> Option<u64>
+    {
+        self.focused.get()
+    }
+
+    fn forward<T, F>(&self, call: F) -> Result<T, BspwmError>
+        where F: FnOnce(&dyn Backend) -> Result<T, BspwmError>
+    {
+        self.calls.set(self.calls.get() + 1);
+        call(self.inner)
+    }
+
+    /**
+      Fetches the trees of all the monitors unless they are already loaded
+    */
+    fn load(&self) -> Result<(), BspwmError>
+    {
+        if self.monitors.borrow().is_some()
+        {
+            return Ok(())
+        }
+
+        let ids = self.forward(|bspc| bspc.query(&vec!("-M")))?;
+
+        let mut monitors = vec!();
+        for id in ids.lines().filter(|line| !line.is_empty())
+        {
+            let tree = self.forward(|bspc| bspc.query(&vec!("-T", "-m", id)))?;
+            let monitor = json::Json::from_str(&tree)
+                .map_err(|e| BspwmError::Unparsable(format!("Monitor {} is not valid json: {}", id, e)))?;
+
+            monitors.push(monitor);
+        }
+
+        *self.monitors.borrow_mut() = Some(monitors);
+        self.dirty.set(false);
+
+        Ok(())
+    }
+
+    /**
+      Answers a query from the snapshot. Returns None if the query is not
+      about a specific node and has to be passed on
+    */
+    fn answer(&self, arguments: &Vec<&str>) -> Option<Result<String, BspwmError>>
+    {
+        let node_query = match (arguments.get(0), arguments.get(1), arguments.get(2))
+        {
+            (Some(&query_type), Some(&"-n"), Some(selector)) => selector_node(selector)
+                .map(|(id, parent)| (query_type, id, parent)),
+            _ => None
+        };
+
+        let (query_type, id, parent) = match (node_query, arguments.as_slice())
+        {
+            (Some(node_query), _) => node_query,
+            (None, &["-N"]) => {
+                return Some(self.load().map(|_| {
+                    let monitors = self.monitors.borrow();
+                    desktop_roots(monitors.as_ref().unwrap())
+                        .iter()
+                        .flat_map(|&(_, _, root)| subtree_ids(root))
+                        .map(|id| format!("{}\n", bspwm::get_node_name(id)))
+                        .collect()
+                }))
+            }
+            _ => return None
+        };
+
+        let names = arguments.get(3) == Some(&"--names");
+        if arguments.len() > 3 + names as usize || (parent && query_type != "-N")
+        {
+            return None
+        }
+
+        //The other queries only depend on where the nodes are in the tree,
+        //which ratio and hidden flag changes don't affect
+        if query_type == "-T" && self.dirty.get()
+        {
+            *self.monitors.borrow_mut() = None;
+        }
+
+        if let Err(e) = self.load()
+        {
+            return Some(Err(e))
+        }
+
+        let monitors = self.monitors.borrow();
+        let location = desktop_roots(monitors.as_ref().unwrap())
+            .into_iter()
+            .filter_map(|(monitor, desktop, root)| find_json_node(root, id).map(|node| (monitor, desktop, root, node)))
+            .next();
+
+        let (monitor, desktop, root, node) = match location
+        {
+            Some(location) => location,
+            None => return Some(Err(BspwmError::NonZeroExit("Descriptor matching failed.".to_string())))
+        };
+
+        let answer = match (query_type, names, parent)
+        {
+            ("-T", false, _) => Some(format!("{}", node)),
+            ("-N", false, false) => json_id(node).map(bspwm::get_node_name),
+            ("-N", false, true) => find_json_parent(root, id).map(bspwm::get_node_name),
+            ("-D", false, _) => json_id(desktop).map(bspwm::get_desktop_name),
+            ("-M", false, _) => json_id(monitor).map(bspwm::get_monitor_name),
+            ("-D", true, _) => desktop.find("name").and_then(|name| name.as_string()).map(String::from),
+            ("-M", true, _) => monitor.find("name").and_then(|name| name.as_string()).map(String::from),
+            _ => return None
+        };
+
+        Some(answer
+            .map(|answer| format!("{}\n", answer))
+            .ok_or(BspwmError::NonZeroExit("Descriptor matching failed.".to_string())))
+    }
+
+    /**
+      Sets a field of a node in the snapshot. Returns false if the node is
+      not in the snapshot
+    */
+    fn set_node_field(&self, id: u64, field: &str, value: json::Json) -> bool
+    {
+        let mut monitors = self.monitors.borrow_mut();
+        let monitors = match monitors.as_mut()
+        {
+            Some(monitors) => monitors,
+            None => return true
+        };
+
+        let node = monitors.iter_mut()
+            .filter_map(|monitor| json_field_mut(monitor, "desktops"))
+            .filter_map(|desktops| desktops.as_array_mut())
+            .flat_map(|desktops| desktops.iter_mut())
+            .filter_map(|desktop| json_field_mut(desktop, "root"))
+            .filter_map(|root| find_json_node_mut(root, id))
+            .next();
+
+        match node
+        {
+            Some(node) => {
+                set_json_field(node, field, value);
+                true
+            }
+            None => false
+        }
+    }
+
+    /**
+      Makes the node the focused node of its desktop and the desktop the
+      focused desktop of its monitor. Returns false if the node is not in
+      the snapshot
+    */
+    fn set_focused(&self, id: u64) -> bool
+    {
+        let mut monitors = self.monitors.borrow_mut();
+        let monitors = match monitors.as_mut()
+        {
+            Some(monitors) => monitors,
+            None => return true
+        };
+
+        for monitor in monitors.iter_mut()
+        {
+            let desktop = json_field_mut(monitor, "desktops")
+                .and_then(|desktops| desktops.as_array_mut())
+                .and_then(|desktops| {
+                    desktops.iter_mut()
+                        .find(|desktop| desktop.find("root").and_then(|root| find_json_node(root, id)).is_some())
+                })
+                .and_then(|desktop| {
+                    set_json_field(desktop, "focusedNodeId", json::Json::U64(id));
+                    json_id(desktop)
+                });
+
+            if let Some(desktop) = desktop
+            {
+                set_json_field(monitor, "focusedDesktopId", json::Json::U64(desktop));
+                return true
+            }
+        }
+
+        false
+    }
+}
+
+impl<'a> Backend for Snapshot<'a>
+{
+    fn query(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        match self.answer(arguments)
+        {
+            Some(answer) => answer,
+            None => self.forward(|bspc| bspc.query(arguments))
+        }
+    }
+
+    fn node(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        let result = self.forward(|bspc| bspc.node(arguments));
+
+        let updated = match (&result, arguments.as_slice())
+        {
+            (&Ok(_), &[node, "-r", ratio]) => match (bspwm::parse_id(node), ratio.parse::<f64>())
+            {
+                (Some(id), Ok(ratio)) => {
+                    self.dirty.set(true);
+                    self.set_node_field(id, "splitRatio", json::Json::F64(ratio))
+                }
+                _ => false
+            },
+            (&Ok(_), &[node, "-g", flag]) if flag == "hidden=on" || flag == "hidden=off" => match bspwm::parse_id(node)
+            {
+                Some(id) => {
+                    self.dirty.set(true);
+                    self.set_node_field(id, "hidden", json::Json::Boolean(flag == "hidden=on"))
+                }
+                None => false
+            },
+            (&Ok(_), &["-f", selector]) => match selector_node(selector)
+            {
+                Some((id, false)) => {
+                    self.focused.set(Some(id));
+                    self.set_focused(id)
+                }
+                _ => false
+            },
+            _ => false
+        };
+
+        if !updated
+        {
+            *self.monitors.borrow_mut() = None;
+        }
+
+        result
+    }
+
+    fn desktop(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        *self.monitors.borrow_mut() = None;
+        self.forward(|bspc| bspc.desktop(arguments))
+    }
+
+    fn config(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        let key = arguments.iter().map(|argument| argument.to_string()).collect::<Vec<_>>();
+
+        if let Some(&(_, ref value)) = self.settings.borrow().iter().find(|&&(ref cached, _)| *cached == key)
+        {
+            return Ok(value.clone())
+        }
+
+        let value = self.forward(|bspc| bspc.config(arguments))?;
+        self.settings.borrow_mut().push((key, value.clone()));
+        Ok(value)
+    }
+
+    fn subscribe(&self, events: &Vec<&str>) -> Result<Box<dyn BufRead>, BspwmError>
+    {
+        self.forward(|bspc| bspc.subscribe(events))
+    }
+
+    fn connect(&self) -> Box<dyn Backend + Send>
+    {
+        self.inner.connect()
+    }
+}
+
+/**
+  Parses a node selector that names a node by id, optionally followed by
+  `#@parent`. Returns the id and whether the parent was selected
+*/
+fn selector_node(selector: &str) -> Option<(u64, bool)>
+{
+    let (node, parent) = match selector.ends_with("#@parent")
+    {
+        true => (&selector[..selector.len() - "#@parent".len()], true),
+        false => (selector, false)
+    };
+
+    let id = match node.starts_with("0x")
+    {
+        true => bspwm::parse_id(node),
+        false => node.parse::<u64>().ok()
+    };
+
+    id.map(|id| (id, parent))
+}
+
+fn json_id(node: &json::Json) -> Option<u64>
+{
+    node.find("id").and_then(|id| id.as_u64())
+}
+
+/**
+  Returns the (monitor, desktop, root node) of every desktop that has nodes
+*/
+fn desktop_roots(monitors: &Vec<json::Json>) -> Vec<(&json::Json, &json::Json, &json::Json)>
+{
+    monitors.iter()
+        .flat_map(|monitor| {
+            monitor.find("desktops")
+                .and_then(|desktops| desktops.as_array())
+                .into_iter()
+                .flat_map(|desktops| desktops.iter())
+                .filter_map(move |desktop| {
+                    desktop.find("root")
+                        .filter(|root| !root.is_null())
+                        .map(|root| (monitor, desktop, root))
+                })
+        })
+        .collect()
+}
+
+/**
+  The children of a json node that are not null
+*/
+fn json_children(node: &json::Json) -> Vec<&json::Json>
+{
+    ["firstChild", "secondChild"].iter()
+        .filter_map(|child| node.find(child))
+        .filter(|child| !child.is_null())
+        .collect()
+}
+
+fn find_json_node(root: &json::Json, id: u64) -> Option<&json::Json>
+{
+    if json_id(root) == Some(id)
+    {
+        return Some(root)
+    }
+
+    json_children(root).into_iter()
+        .filter_map(|child| find_json_node(child, id))
+        .next()
+}
+
+fn find_json_node_mut(root: &mut json::Json, id: u64) -> Option<&mut json::Json>
+{
+    if json_id(root) == Some(id)
+    {
+        return Some(root)
+    }
+
+    //Find the child to descend into first so that only one mutable borrow is taken
+    let child = ["firstChild", "secondChild"].iter()
+        .find(|child| root.find(child).and_then(|child| find_json_node(child, id)).is_some())?;
+
+    find_json_node_mut(json_field_mut(root, child)?, id)
+}
+
+fn json_field_mut<'a>(node: &'a mut json::Json, field: &str) -> Option<&'a mut json::Json>
+{
+    node.as_object_mut().and_then(|object| object.get_mut(field))
+}
+
+fn find_json_parent(root: &json::Json, id: u64) -> Option<u64>
+{
+    let children = json_children(root);
+
+    if children.iter().any(|child| json_id(child) == Some(id))
+    {
+        return json_id(root)
+    }
+
+    children.into_iter()
+        .filter_map(|child| find_json_parent(child, id))
+        .next()
+}
+
+fn subtree_ids(root: &json::Json) -> Vec<u64>
+{
+    let mut ids = json_id(root).into_iter().collect::<Vec<_>>();
+
+    for child in json_children(root)
+    {
+        ids.append(&mut subtree_ids(child));
+    }
+
+    ids
+}
+
+fn set_json_field(node: &mut json::Json, field: &str, value: json::Json)
+{
+    if let Some(node) = node.as_object_mut()
+    {
+        node.insert(field.to_string(), value);
+    }
+}
+
+
+
+/**
+  In memory backend which answers queries from a fixed bspwm tree and
+  records every command that it is sent.
+*/
+#[cfg(test)]
+pub struct MockBackend
+{
+    tree: RefCell<json::Json>,
+    focused: Cell<Option<u64>>,
+    commands: ::std::sync::Arc<::std::sync::Mutex<Vec<String>>>,
+    events: RefCell<String>,
+    window_gap: Cell<u64>,
+    unavailable: Cell<bool>,
+    failing_commands: RefCell<Option<String>>
+}
+
+#[cfg(test)]
+impl MockBackend
+{
+    pub fn new(tree: &str) -> MockBackend
+    {
+        MockBackend {
+            tree: RefCell::new(json::Json::from_str(tree).unwrap()),
+            focused: Cell::new(None),
+            commands: ::std::sync::Arc::new(::std::sync::Mutex::new(vec!())),
+            events: RefCell::new(String::new()),
+            window_gap: Cell::new(6),
+            unavailable: Cell::new(false),
+            failing_commands: RefCell::new(None)
+        }
+    }
+
+    /**
+      Creates a mock backend serving the tree in sample_tree.json
+    */
+    pub fn from_sample() -> MockBackend
+    {
+        use std::io::prelude::*;
+        use std::fs::File;
+
+        let mut f = File::open("sample_tree.json").unwrap();
+        let mut s = String::new();
+        f.read_to_string(&mut s).unwrap();
+
+        MockBackend::new(&s)
+    }
+
+    pub fn set_tree(&self, tree: &str)
+    {
+        *self.tree.borrow_mut() = json::Json::from_str(tree).unwrap();
+    }
+
+    pub fn set_focused(&self, node: Option<u64>)
+    {
+        self.focused.set(node);
+    }
+
+    /**
+      Returns all commands that have been sent so far, as they would be passed
+      to bspc
+    */
+    pub fn commands(&self) -> Vec<String>
+    {
+        self.commands.lock().unwrap().clone()
+    }
+
+    pub fn clear_commands(&self)
+    {
+        self.commands.lock().unwrap().clear();
+    }
+
+    /**
+      Sets the window_gap setting. Defaults to bspwm's default of 6
+    */
+    pub fn set_window_gap(&self, window_gap: u64)
+    {
+        self.window_gap.set(window_gap);
+    }
+
+    /**
+      Makes every query and command fail as if bspwm could not be reached
+    */
+    pub fn set_unavailable(&self, unavailable: bool)
+    {
+        self.unavailable.set(unavailable);
+    }
+
+    /**
+      Makes the node commands that contain `pattern` fail. Failed commands
+      are not recorded
+    */
+    pub fn fail_commands(&self, pattern: &str)
+    {
+        *self.failing_commands.borrow_mut() = Some(pattern.to_string());
+    }
+
+    fn check_available(&self) -> Result<(), BspwmError>
+    {
+        match self.unavailable.get()
+        {
+            true => Err(BspwmError::Spawn("bspwm is unavailable".to_string())),
+            false => Ok(())
+        }
+    }
+
+    /**
+      Sets the lines that the next subscription will produce
+    */
+    pub fn set_events(&self, events: &str)
+    {
+        *self.events.borrow_mut() = events.to_string();
+    }
+
+    fn all_nodes(&self) -> Vec<u64>
+    {
+        fn inner(node: &json::Json, buffer: &mut Vec<u64>)
+        {
+            if node.is_null()
+            {
+                return
+            }
+
+            buffer.push(node.find("id").unwrap().as_u64().unwrap());
+            inner(node.find("firstChild").unwrap(), buffer);
+            inner(node.find("secondChild").unwrap(), buffer);
+        }
+
+        let mut result = vec!();
+        inner(&self.tree.borrow(), &mut result);
+        result
+    }
+
+    /**
+      Resolves a node selector to an id. Only plain ids, `@/`, `<id>#@parent`
+      and the empty (focused) selector are supported
+    */
+    fn resolve_selector(&self, selector: Option<&&str>) -> Option<u64>
+    {
+        match selector
+        {
+            None => self.focused.get(),
+            Some(&"@/") => self.tree.borrow().find("id").and_then(|id| id.as_u64()),
+            Some(selector) if selector.ends_with("#@parent") =>
+            {
+                let child = &selector[..selector.len() - "#@parent".len()];
+                self.resolve_selector(Some(&child))
+                    .and_then(|child| find_json_parent(&self.tree.borrow(), child))
+            }
+            Some(selector) =>
+            {
+                let id = match selector.starts_with("0x")
+                {
+                    true => u64::from_str_radix(&selector[2..], 16).ok(),
+                    false => selector.parse::<u64>().ok()
+                };
+
+                id.and_then(|id| {
+                    match self.all_nodes().contains(&id)
+                    {
+                        true => Some(id),
+                        false => None
+                    }
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl Backend for MockBackend
+{
+    fn query(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        self.check_available()?;
+
+        let format_ids = |ids: Vec<u64>| {
+            ids.iter().map(|id| format!("0x{:08X}\n", id)).collect::<String>()
+        };
+
+        match (arguments.get(0), arguments.get(1))
+        {
+            //The mock has a single monitor with a single desktop
+            (Some(&"-T"), Some(&"-m")) =>
+            {
+                Ok(format!(
+                    r#"{{"id":2,"name":"DP-1","desktops":[{{"id":1,"name":"I","root":{}}}]}}"#,
+                    self.tree.borrow()
+                ))
+            }
+            (Some(&"-T"), Some(&"-n")) =>
+            {
+                match self.resolve_selector(arguments.get(2))
+                    .and_then(|id| find_json_node(&self.tree.borrow(), id).cloned())
+                {
+                    Some(subtree) => Ok(format!("{}", subtree)),
+                    None => Err(BspwmError::NonZeroExit("Descriptor matching failed".to_string()))
+                }
+            }
+            (Some(&"-N"), None) | (Some(&"-N"), Some(&"-d")) => Ok(format_ids(self.all_nodes())),
+            (Some(&"-N"), Some(&"-n")) =>
+            {
+                Ok(format_ids(self.resolve_selector(arguments.get(2)).into_iter().collect()))
+            }
+            (Some(&"-D"), _) | (Some(&"-M"), _) if arguments.contains(&"--names") =>
+            {
+                match arguments[0]
+                {
+                    "-D" => Ok("I\n".to_string()),
+                    _ => Ok("DP-1\n".to_string())
+                }
+            }
+            (Some(&"-D"), _) => Ok(format_ids(vec!(1))),
+            (Some(&"-M"), _) => Ok(format_ids(vec!(2))),
+            _ => Err(BspwmError::Unparsable(format!("Unsupported mock query {:?}", arguments)))
+        }
+    }
+
+    fn node(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        self.check_available()?;
+
+        let mut command = vec!("node");
+        command.extend(arguments.iter().cloned());
+        let command = command.join(" ");
+
+        if let Some(ref pattern) = *self.failing_commands.borrow()
+        {
+            if command.contains(pattern.as_str())
+            {
+                return Err(BspwmError::NonZeroExit(format!("{} failed", command)))
+            }
+        }
+
+        self.commands.lock().unwrap().push(command);
+        Ok(String::new())
+    }
+
+    fn desktop(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        self.check_available()?;
+
+        let mut command = vec!("desktop");
+        command.extend(arguments.iter().cloned());
+
+        self.commands.lock().unwrap().push(command.join(" "));
+        Ok(String::new())
+    }
+
+    fn config(&self, arguments: &Vec<&str>) -> Result<String, BspwmError>
+    {
+        self.check_available()?;
+
+        match arguments.last()
+        {
+            Some(&"window_gap") => Ok(format!("{}\n", self.window_gap.get())),
+            _ => Err(BspwmError::Unparsable(format!("Unsupported mock setting {:?}", arguments)))
+        }
+    }
+
+    fn subscribe(&self, _events: &Vec<&str>) -> Result<Box<dyn BufRead>, BspwmError>
+    {
+        let events = self.events.borrow().clone();
+        Ok(Box::new(::std::io::Cursor::new(events.into_bytes())))
+    }
+
+    /**
+      The new backend has the same state as this one at the time it is
+      created and records its commands in the same list
+    */
+    fn connect(&self) -> Box<dyn Backend + Send>
+    {
+        Box::new(MockBackend {
+            tree: RefCell::new(self.tree.borrow().clone()),
+            focused: Cell::new(self.focused.get()),
+            commands: self.commands.clone(),
+            events: RefCell::new(self.events.borrow().clone()),
+            window_gap: Cell::new(self.window_gap.get()),
+            unavailable: Cell::new(self.unavailable.get()),
+            failing_commands: RefCell::new(self.failing_commands.borrow().clone())
+        })
+    }
+}
+
+
+
+#[cfg(test)]
+mod backend_tests
+{
+    use super::*;
+
+    #[test]
+    fn mock_query_test()
+    {
+        let backend = MockBackend::from_sample();
+        backend.set_focused(Some(29526298));
+
+        assert_eq!(backend.query(&vec!("-N", "-n")), Ok("0x01C2891A\n".to_string()));
+        assert_eq!(backend.query(&vec!("-N", "-n", "0x01C2891A")), Ok("0x01C2891A\n".to_string()));
+        assert_eq!(backend.query(&vec!("-N", "-n", "0x0")), Ok("".to_string()));
+        assert_eq!(backend.query(&vec!("-N")).unwrap().lines().count(), 11);
+        let subtree = json::Json::from_str(&backend.query(&vec!("-T", "-n", "4194640")).unwrap()).unwrap();
+        assert_eq!(subtree.find("id").unwrap().as_u64(), Some(4194640));
+        assert!(backend.query(&vec!("-T", "-n", "5")).is_err());
+
+        backend.node(&vec!("0x400040", "-B")).unwrap();
+        assert_eq!(backend.commands(), vec!("node 0x400040 -B".to_string()));
+    }
+
+    #[test]
+    fn snapshot_test()
+    {
+        let mock = MockBackend::from_sample();
+        let snapshot = Snapshot::new(&mock);
+
+        //The first query fetches the monitors and their trees, the next ones
+        //are answered from them
+        bspwm::get_node_tree(&snapshot, 4194640).unwrap();
+        assert_eq!(snapshot.calls(), 2);
+
+        assert_eq!(bspwm::get_node_parent(&snapshot, 4194640), Ok(Some(4194636)));
+        assert_eq!(bspwm::get_node_parent(&snapshot, 4194621), Ok(None));
+        assert_eq!(bspwm::get_node_desktop_name(&snapshot, 4194640), Ok("I".to_string()));
+        assert_eq!(bspwm::get_node_monitor(&snapshot, 4194640), Ok(2));
+        assert_eq!(bspwm::get_node_exists(&snapshot, 29526298), Ok(true));
+        assert_eq!(bspwm::get_node_tree(&snapshot, 5), Err(BspwmError::MissingNode(5)));
+        assert_eq!(snapshot.calls(), 2);
+
+        //Settings are only read once
+        assert_eq!(bspwm::get_node_window_gap(&snapshot, 4194640), Ok(6));
+        assert_eq!(bspwm::get_node_window_gap(&snapshot, 4194640), Ok(6));
+        assert_eq!(snapshot.calls(), 3);
+
+        //Ratio changes move other nodes, so the next tree is fetched again.
+        //Queries about where nodes are are still answered from the snapshot
+        bspwm::node_change_ratio(&snapshot, "0x400150", 0.25).unwrap();
+        assert_eq!(bspwm::get_node_parent(&snapshot, 4194640), Ok(Some(4194636)));
+        assert_eq!(snapshot.calls(), 4);
+        bspwm::get_node_tree(&snapshot, 4194640).unwrap();
+        bspwm::get_node_tree(&snapshot, 4194640).unwrap();
+        assert_eq!(snapshot.calls(), 6);
+
+        //Other commands make the next query fetch the trees again
+        bspwm::node_swap(&snapshot, 29526298, 29541363).unwrap();
+        assert_eq!(bspwm::get_node_parent(&snapshot, 4194640), Ok(Some(4194636)));
+        assert_eq!(snapshot.calls(), 9);
+        assert_eq!(mock.commands().len(), 2);
+    }
+
+    #[test]
+    fn snapshot_update_test()
+    {
+        let mock = MockBackend::from_sample();
+        let snapshot = Snapshot::new(&mock);
+
+        bspwm::get_node_tree(&snapshot, 4194621).unwrap();
+        assert_eq!(snapshot.calls(), 2);
+
+        //Focusing doesn't move any nodes, so the trees are kept
+        bspwm::node_focus(&snapshot, 29541313).unwrap();
+        bspwm::get_node_tree(&snapshot, 4194621).unwrap();
+        assert_eq!(snapshot.focused_node(), Some(29541313));
+        assert_eq!(snapshot.calls(), 3);
+
+        //Hidden windows give their space to other windows, so like after
+        //ratio changes only the next tree is fetched again
+        bspwm::node_set_hidden(&snapshot, 29541339, true).unwrap();
+        assert_eq!(bspwm::get_node_parent(&snapshot, 29541339), Ok(Some(4194638)));
+        assert_eq!(snapshot.calls(), 4);
+        bspwm::get_node_tree(&snapshot, 4194638).unwrap();
+        assert_eq!(snapshot.calls(), 6);
+    }
+
+    #[test]
+    fn child_output_test()
+    {
+        let output = ChildOutput::spawn(process::Command::new("sh").args(&["-c", "echo node_add; echo node_remove"]))
+            .unwrap();
+        let pid = output.child.id();
+
+        let lines = output.lines().map(|line| line.unwrap()).collect::<Vec<_>>();
+        assert_eq!(lines, vec!("node_add", "node_remove"));
+
+        //The program has been waited for, so it is not a zombie
+        assert!(!PathBuf::from(format!("/proc/{}", pid)).exists());
+    }
+
+    #[test]
+    fn default_socket_path_test()
+    {
+        assert_eq!(default_socket_path(":0"), Some(PathBuf::from("/tmp/bspwm_0_0-socket")));
+        assert_eq!(
+                default_socket_path("localhost:10.1"),
+                Some(PathBuf::from("/tmp/bspwmlocalhost_10_1-socket"))
+            );
+        assert_eq!(default_socket_path("garbage"), None);
+        assert_eq!(default_socket_path(":x"), None);
+    }
+
+    #[test]
+    fn socket_message_test()
+    {
+        use std::os::unix::net::UnixListener;
+        use std::thread;
+        use std::fs;
+
+        let path = env::temp_dir().join(format!("rspwm-socket-test-{}", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            let mut messages = vec!();
+            let replies: Vec<&[u8]> = vec!(b"0x00400044\n", b"\x07Descriptor matching failed.\n");
+
+            for reply in replies
+            {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buffer = [0; 256];
+                let length = stream.read(&mut buffer).unwrap();
+                messages.push(buffer[..length].to_vec());
+                stream.write_all(reply).unwrap();
+            }
+            messages
+        });
+
+        let socket = BspwmSocket::new(path.clone());
+        assert_eq!(socket.query(&vec!("-N", "-n")), Ok("0x00400044\n".to_string()));
+        assert_eq!(
+                socket.node(&vec!("-f", "west")),
+                Err(BspwmError::NonZeroExit("Descriptor matching failed.".to_string()))
+            );
+
+        let messages = server.join().unwrap();
+        assert_eq!(messages[0], b"query\0-N\0-n\0".to_vec());
+        assert_eq!(messages[1], b"node\0-f\0west\0".to_vec());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
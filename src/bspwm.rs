@@ -1,20 +1,51 @@
 
-extern crate regex;
-use regex::Regex;
-
 extern crate rustc_serialize;
 use rustc_serialize::json;
 
 use std::string::String;
 use std::vec::Vec;
+use std::fmt;
 
-use subprogram::call_program;
+use backend::Backend;
 
 ////////////////////////////////////////////////////////////////////////////////
 //                          Bspwm related datatypes
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+/**
+  Everything that can go wrong when talking to bspwm
+*/
+#[derive(Clone, PartialEq, Debug, RustcEncodable, RustcDecodable)]
+pub enum BspwmError
+{
+    /// bspc could not be started or the bspwm socket could not be used
+    Spawn(String),
+    /// bspwm reported that the command failed
+    NonZeroExit(String),
+    /// bspwm replied with something that could not be parsed
+    Unparsable(String),
+    /// The node does not exist
+    MissingNode(u64),
+    /// The tree reported by bspwm does not match the tree model
+    SchemaMismatch(String)
+}
+
+impl fmt::Display for BspwmError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            BspwmError::Spawn(ref msg) => write!(f, "Failed to reach bspwm: {}", msg),
+            BspwmError::NonZeroExit(ref msg) => write!(f, "bspwm command failed: {}", msg),
+            BspwmError::Unparsable(ref msg) => write!(f, "Unexpected output from bspwm: {}", msg),
+            BspwmError::MissingNode(node) => write!(f, "Node {} does not exist", get_node_name(node)),
+            BspwmError::SchemaMismatch(ref msg) => write!(f, "Unexpected bspwm tree: {}", msg)
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, RustcEncodable, RustcDecodable)]
 pub enum FocusTarget
 {
     Node(u64),
@@ -33,13 +64,29 @@ pub enum ResizeDirection
 }
 
 
-#[derive(PartialEq, Clone, RustcEncodable)]
+#[derive(PartialEq, Eq, Clone, Debug, RustcEncodable, RustcDecodable)]
 pub enum SplitDirection
 {
     Horizontal,
     Vertical
 }
 
+impl SplitDirection
+{
+    /**
+      Returns the directions of the first and second child of a node that
+      is split in this direction
+    */
+    pub fn child_directions(&self) -> (CardinalDirection, CardinalDirection)
+    {
+        match *self
+        {
+            SplitDirection::Horizontal => (CardinalDirection::North, CardinalDirection::South),
+            SplitDirection::Vertical => (CardinalDirection::West, CardinalDirection::East)
+        }
+    }
+}
+
 
 
 #[derive(Debug, Eq, PartialEq)]
@@ -52,7 +99,7 @@ pub enum Children
 
 
 
-#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+#[derive(Clone, Debug, PartialEq, Eq, RustcEncodable, RustcDecodable)]
 pub enum CardinalDirection
 {
     North,
@@ -85,6 +132,490 @@ impl CardinalDirection
             CardinalDirection::West => "west"
         }
     }
+
+    pub fn opposite(&self) -> CardinalDirection
+    {
+        match *self
+        {
+            CardinalDirection::North => CardinalDirection::South,
+            CardinalDirection::South => CardinalDirection::North,
+            CardinalDirection::East => CardinalDirection::West,
+            CardinalDirection::West => CardinalDirection::East
+        }
+    }
+}
+
+
+
+
+/**
+  Events reported by `bspc subscribe` that affect the stacks
+*/
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event
+{
+    NodeFocus(u64),
+    /// The desktop that the node was added to
+    NodeAdd(u64),
+    /// The desktop that the node was removed from
+    NodeRemove(u64),
+    /// The desktops that the node was moved from and to
+    NodeTransfer(u64, u64),
+    DesktopFocus
+}
+
+impl Event
+{
+    /**
+      The names of the events as passed to `bspc subscribe`
+    */
+    pub fn names() -> Vec<&'static str>
+    {
+        vec!("node_focus", "node_add", "node_remove", "node_transfer", "desktop_focus")
+    }
+
+    /**
+      Parses a line of subscription output. None if the event is not one
+      of the events in `Event::names` or if it is malformed
+    */
+    pub fn parse(line: &str) -> Option<Event>
+    {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+
+        match words.first()
+        {
+            //node_focus <monitor_id> <desktop_id> <node_id>
+            Some(&"node_focus") =>
+            {
+                words.get(3)
+                    .and_then(|id| parse_id(id))
+                    .map(Event::NodeFocus)
+            },
+            //node_add <monitor_id> <desktop_id> <ip_id> <node_id>
+            Some(&"node_add") => words.get(2).and_then(|id| parse_id(id)).map(Event::NodeAdd),
+            //node_remove <monitor_id> <desktop_id> <node_id>
+            Some(&"node_remove") => words.get(2).and_then(|id| parse_id(id)).map(Event::NodeRemove),
+            //node_transfer <src_monitor_id> <src_desktop_id> <src_node_id>
+            //              <dst_monitor_id> <dst_desktop_id> <dst_node_id>
+            Some(&"node_transfer") =>
+            {
+                let source = words.get(2).and_then(|id| parse_id(id));
+                let destination = words.get(5).and_then(|id| parse_id(id));
+
+                source.and_then(|source| destination.map(|destination| Event::NodeTransfer(source, destination)))
+            },
+            Some(&"desktop_focus") => Some(Event::DesktopFocus),
+            _ => None
+        }
+    }
+}
+
+/**
+  Parses a 0x prefixed id as printed by bspwm
+*/
+pub fn parse_id(id: &str) -> Option<u64>
+{
+    match id.starts_with("0x")
+    {
+        true => u64::from_str_radix(&id[2..], 16).ok(),
+        false => None
+    }
+}
+
+
+
+////////////////////////////////////////////////////////////////////////////////
+//                          Typed bspwm tree model
+////////////////////////////////////////////////////////////////////////////////
+
+/**
+  A rectangle as reported by bspwm in the `rectangle`, `tiledRectangle` and
+  `floatingRectangle` fields
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rectangle
+{
+    pub x: i64,
+    pub y: i64,
+    pub width: u64,
+    pub height: u64
+}
+
+/**
+  Minimum size of a node. Only reported by newer versions of bspwm
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Constraints
+{
+    pub min_width: u64,
+    pub min_height: u64
+}
+
+/**
+  The preselection of a node, if any
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Presel
+{
+    pub split_direction: CardinalDirection,
+    pub split_ratio: f64
+}
+
+/**
+  The boolean flags that bspwm keeps for every node
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeFlags
+{
+    pub vacant: bool,
+    pub hidden: bool,
+    pub sticky: bool,
+    pub private: bool,
+    pub locked: bool,
+    pub marked: bool
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientState
+{
+    Tiled,
+    PseudoTiled,
+    Floating,
+    Fullscreen
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StackLayer
+{
+    Below,
+    Normal,
+    Above
+}
+
+/**
+  The window that is displayed in a leaf node
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Client
+{
+    pub class_name: String,
+    pub instance_name: String,
+    pub border_width: u64,
+    pub state: ClientState,
+    pub last_state: ClientState,
+    pub layer: StackLayer,
+    pub last_layer: StackLayer,
+    pub urgent: bool,
+    pub shown: bool,
+    pub tiled_rectangle: Rectangle,
+    pub floating_rectangle: Rectangle
+}
+
+/**
+  A node in the bspwm tree. Internal nodes have exactly two children, leaves
+  have none and usually hold a client.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node
+{
+    pub id: u64,
+    pub split_type: SplitDirection,
+    pub split_ratio: f64,
+    pub birth_rotation: Option<i64>,
+    pub flags: NodeFlags,
+    pub presel: Option<Presel>,
+    pub rectangle: Rectangle,
+    pub constraints: Option<Constraints>,
+    pub children: Option<Box<(Node, Node)>>,
+    pub client: Option<Client>
+}
+
+impl Node
+{
+    /**
+      Parses the output of `bspc query -T -n <node>`
+    */
+    pub fn parse(string: &str) -> Result<Node, BspwmError>
+    {
+        match json::Json::from_str(string)
+        {
+            Ok(value) => Node::from_json(&value),
+            Err(e) => Err(BspwmError::Unparsable(format!("Failed to parse bspwm tree: {}", e)))
+        }
+    }
+
+    /**
+      Builds a node from its json representation. All fields are checked
+      so that changes to the bspwm output are reported here rather than
+      further down in the stacking code
+    */
+    pub fn from_json(value: &json::Json) -> Result<Node, BspwmError>
+    {
+        Node::from_json_fields(value).map_err(BspwmError::SchemaMismatch)
+    }
+
+    fn from_json_fields(value: &json::Json) -> Result<Node, String>
+    {
+        let object = expect_fields(
+                value,
+                "node",
+                &[
+                    "id", "splitType", "splitRatio", "vacant", "hidden", "sticky",
+                    "private", "locked", "presel", "rectangle", "firstChild",
+                    "secondChild", "client"
+                ],
+                &["birthRotation", "marked", "constraints"]
+            )?;
+
+        let split_type = match string_field(object, "node", "splitType")?.as_ref()
+        {
+            "horizontal" => SplitDirection::Horizontal,
+            "vertical" => SplitDirection::Vertical,
+            other => return Err(format!("Unknown splitType value '{}'", other))
+        };
+
+        let presel = match object.get("presel").unwrap()
+        {
+            &json::Json::Null => None,
+            other => Some(Presel::from_json(other)?)
+        };
+
+        let constraints = match object.get("constraints")
+        {
+            None | Some(&json::Json::Null) => None,
+            Some(other) => Some(Constraints::from_json(other)?)
+        };
+
+        let birth_rotation = match object.get("birthRotation")
+        {
+            None => None,
+            Some(_) => Some(i64_field(object, "node", "birthRotation")?)
+        };
+
+        let marked = match object.get("marked")
+        {
+            None => false,
+            Some(_) => bool_field(object, "node", "marked")?
+        };
+
+        let children = match (object.get("firstChild").unwrap(), object.get("secondChild").unwrap())
+        {
+            (&json::Json::Null, &json::Json::Null) => None,
+            (&json::Json::Null, _) | (_, &json::Json::Null) =>
+            {
+                return Err("Node has exactly one child".to_string())
+            },
+            (first, second) =>
+            {
+                Some(Box::new((Node::from_json_fields(first)?, Node::from_json_fields(second)?)))
+            }
+        };
+
+        let client = match object.get("client").unwrap()
+        {
+            &json::Json::Null => None,
+            other => Some(Client::from_json(other)?)
+        };
+
+        Ok(Node {
+            id: u64_field(object, "node", "id")?,
+            split_type: split_type,
+            split_ratio: f64_field(object, "node", "splitRatio")?,
+            birth_rotation: birth_rotation,
+            flags: NodeFlags {
+                vacant: bool_field(object, "node", "vacant")?,
+                hidden: bool_field(object, "node", "hidden")?,
+                sticky: bool_field(object, "node", "sticky")?,
+                private: bool_field(object, "node", "private")?,
+                locked: bool_field(object, "node", "locked")?,
+                marked: marked
+            },
+            presel: presel,
+            rectangle: Rectangle::from_json(object.get("rectangle").unwrap())?,
+            constraints: constraints,
+            children: children,
+            client: client
+        })
+    }
+}
+
+impl Rectangle
+{
+    fn from_json(value: &json::Json) -> Result<Rectangle, String>
+    {
+        let object = expect_fields(value, "rectangle", &["x", "y", "width", "height"], &[])?;
+
+        Ok(Rectangle {
+            x: i64_field(object, "rectangle", "x")?,
+            y: i64_field(object, "rectangle", "y")?,
+            width: u64_field(object, "rectangle", "width")?,
+            height: u64_field(object, "rectangle", "height")?
+        })
+    }
+}
+
+impl Constraints
+{
+    fn from_json(value: &json::Json) -> Result<Constraints, String>
+    {
+        let object = expect_fields(value, "constraints", &["min_width", "min_height"], &[])?;
+
+        Ok(Constraints {
+            min_width: u64_field(object, "constraints", "min_width")?,
+            min_height: u64_field(object, "constraints", "min_height")?
+        })
+    }
+}
+
+impl Presel
+{
+    fn from_json(value: &json::Json) -> Result<Presel, String>
+    {
+        let object = expect_fields(value, "presel", &["splitDir", "splitRatio"], &[])?;
+
+        let direction_string = string_field(object, "presel", "splitDir")?;
+        let split_direction = match CardinalDirection::from_str(&direction_string)
+        {
+            Some(direction) => direction,
+            None => return Err(format!("Unknown splitDir value '{}'", direction_string))
+        };
+
+        Ok(Presel {
+            split_direction: split_direction,
+            split_ratio: f64_field(object, "presel", "splitRatio")?
+        })
+    }
+}
+
+impl ClientState
+{
+    fn from_str(string: &str) -> Result<ClientState, String>
+    {
+        match string
+        {
+            "tiled" => Ok(ClientState::Tiled),
+            "pseudo_tiled" => Ok(ClientState::PseudoTiled),
+            "floating" => Ok(ClientState::Floating),
+            "fullscreen" => Ok(ClientState::Fullscreen),
+            other => Err(format!("Unknown client state '{}'", other))
+        }
+    }
+}
+
+impl StackLayer
+{
+    fn from_str(string: &str) -> Result<StackLayer, String>
+    {
+        match string
+        {
+            "below" => Ok(StackLayer::Below),
+            "normal" => Ok(StackLayer::Normal),
+            "above" => Ok(StackLayer::Above),
+            other => Err(format!("Unknown layer '{}'", other))
+        }
+    }
+}
+
+impl Client
+{
+    fn from_json(value: &json::Json) -> Result<Client, String>
+    {
+        let object = expect_fields(
+                value,
+                "client",
+                &[
+                    "className", "instanceName", "borderWidth", "state", "lastState",
+                    "layer", "lastLayer", "urgent", "shown", "tiledRectangle",
+                    "floatingRectangle"
+                ],
+                &[]
+            )?;
+
+        Ok(Client {
+            class_name: string_field(object, "client", "className")?,
+            instance_name: string_field(object, "client", "instanceName")?,
+            border_width: u64_field(object, "client", "borderWidth")?,
+            state: ClientState::from_str(&string_field(object, "client", "state")?)?,
+            last_state: ClientState::from_str(&string_field(object, "client", "lastState")?)?,
+            layer: StackLayer::from_str(&string_field(object, "client", "layer")?)?,
+            last_layer: StackLayer::from_str(&string_field(object, "client", "lastLayer")?)?,
+            urgent: bool_field(object, "client", "urgent")?,
+            shown: bool_field(object, "client", "shown")?,
+            tiled_rectangle: Rectangle::from_json(object.get("tiledRectangle").unwrap())?,
+            floating_rectangle: Rectangle::from_json(object.get("floatingRectangle").unwrap())?
+        })
+    }
+}
+
+/**
+  Checks that a json value is an object containing all the required fields
+  and nothing but the required and optional fields
+*/
+fn expect_fields<'a>(
+        value: &'a json::Json,
+        context: &str,
+        required: &[&str],
+        optional: &[&str]
+    ) -> Result<&'a json::Object, String>
+{
+    let object = match value.as_object()
+    {
+        Some(object) => object,
+        None => return Err(format!("Expected {} to be an object, got {}", context, value))
+    };
+
+    for field in required
+    {
+        if !object.contains_key(*field)
+        {
+            return Err(format!("Missing field '{}' in {}", field, context));
+        }
+    }
+
+    for key in object.keys()
+    {
+        if !required.contains(&key.as_str()) && !optional.contains(&key.as_str())
+        {
+            return Err(format!("Unexpected field '{}' in {}", key, context));
+        }
+    }
+
+    Ok(object)
+}
+
+fn wrong_type(context: &str, field: &str, expected: &str) -> String
+{
+    format!("Expected field '{}' in {} to be {}", field, context, expected)
+}
+
+fn u64_field(object: &json::Object, context: &str, field: &str) -> Result<u64, String>
+{
+    object.get(field).and_then(|value| value.as_u64())
+        .ok_or(wrong_type(context, field, "an unsigned integer"))
+}
+
+fn i64_field(object: &json::Object, context: &str, field: &str) -> Result<i64, String>
+{
+    object.get(field).and_then(|value| value.as_i64())
+        .ok_or(wrong_type(context, field, "an integer"))
+}
+
+fn f64_field(object: &json::Object, context: &str, field: &str) -> Result<f64, String>
+{
+    object.get(field).and_then(|value| value.as_f64())
+        .ok_or(wrong_type(context, field, "a number"))
+}
+
+fn bool_field(object: &json::Object, context: &str, field: &str) -> Result<bool, String>
+{
+    object.get(field).and_then(|value| value.as_boolean())
+        .ok_or(wrong_type(context, field, "a boolean"))
+}
+
+fn string_field(object: &json::Object, context: &str, field: &str) -> Result<String, String>
+{
+    object.get(field).and_then(|value| value.as_string()).map(|s| s.to_string())
+        .ok_or(wrong_type(context, field, "a string"))
 }
 
 
@@ -94,10 +625,11 @@ impl CardinalDirection
 //                  Query and low level bspwm commands
 ////////////////////////////////////////////////////////////////////////////////
 
-fn general_query(query_type: &str, flags: Vec<(&str, &str)>) -> Result<Vec<u64>, String>
+fn general_query(bspc: &dyn Backend, query_type: &str, flags: Vec<(&str, &str)>)
+    -> Result<Vec<u64>, BspwmError>
 {
     //Bspc is weird and interprets the query "" as something other than no parameters
-    let mut arguments = vec!("query", query_type);
+    let mut arguments = vec!(query_type);
 
     for (flag, selector) in flags
     {
@@ -113,33 +645,22 @@ fn general_query(query_type: &str, flags: Vec<(&str, &str)>) -> Result<Vec<u64>,
     }
 
     //Actualy run the query
-    let node_string = call_program("bspc", &arguments).unwrap();
-
-    //Regex for checking if a string contains nodes
-    let query_check = Regex::new(r"0x[0123456789ABCDEF]*").unwrap();
+    let node_string = bspc.query(&arguments)?;
 
-    if query_check.find(&node_string) != None || node_string == "".to_string()
-    {
-        Ok(node_string.split("\n")
-                .filter(|s| //BSPWM places a \n after all results which results in a trailing result
-                {
-                    s.len() != 0
-                })
-                .map( |s| //Parse as u64
-                {
-                    //Remove 0x and parse the rest as a hexadecimal number
-                    u64::from_str_radix(&s[2..], 16).unwrap()
-                })
-                .collect())
-    }
-    else
-    {
-        Err(format!("Node query failed, query returned {}", node_string))
-    }
+    //BSPWM places a \n after all results which results in a trailing result
+    node_string.split("\n")
+        .filter(|s| s.len() != 0)
+        .map(|s| {
+            match parse_id(s)
+            {
+                Some(id) => Ok(id),
+                None => Err(BspwmError::Unparsable(format!("Node query returned {}", node_string)))
+            }
+        })
+        .collect()
 }
 
 
-//TODO: Return error instead of option
 /**
   Runs a bspwm node query with the specified selector and optionally the specified flag
   before it
@@ -148,75 +669,297 @@ fn general_query(query_type: &str, flags: Vec<(&str, &str)>) -> Result<Vec<u64>,
   bspc query -N <flag> <selector>
   ```
  */
-pub fn node_query_with_flag(selector: &str, flag: &str) -> Option<Vec<u64>>
+pub fn node_query_with_flag(bspc: &dyn Backend, selector: &str, flag: &str)
+    -> Result<Vec<u64>, BspwmError>
+{
+    general_query(bspc, "-N", vec!((flag, selector)))
+}
+
+
+/**
+    Runs bspc query -N -n $selector
+*/
+pub fn node_query(bspc: &dyn Backend, selector: &str) -> Result<Vec<u64>, BspwmError>
+{
+    node_query_with_flag(bspc, selector, "-n")
+}
+
+
+/**
+  Runs bspc query -D -d $selector.
+  Err if bspwm returns something unexpected
+*/
+pub fn desktop_query(bspc: &dyn Backend, selector: &str) -> Result<Vec<u64>, BspwmError>
+{
+    general_query(bspc, "-D", vec!(("-d", selector)))
+}
+
+
+/**
+  Runs bspc query -M -m $selector.
+  Err if bspwm returns something unexpected
+*/
+pub fn monitor_query(bspc: &dyn Backend, selector: &str) -> Result<Vec<u64>, BspwmError>
+{
+    general_query(bspc, "-M", vec!(("-m", selector)))
+}
+
+
+/**
+  Runs bspc query -<query_type> -n $node --names and returns the first name
+*/
+fn name_query(bspc: &dyn Backend, query_type: &str, node: u64) -> Result<String, BspwmError>
+{
+    let names = for_node(node, bspc.query(&vec!(query_type, "-n", &get_node_name(node), "--names")))?;
+
+    names.lines().next()
+        .map(|name| name.to_string())
+        .ok_or(BspwmError::MissingNode(node))
+}
+
+
+/**
+  Tries to get the root node of the specified desktop
+*/
+pub fn desktop_top_node_query(bspc: &dyn Backend, desktop: u64) -> Result<Option<u64>, BspwmError>
+{
+    let all_nodes = general_query(bspc, "-N", vec!(("-d", &get_desktop_name(desktop))))?;
+
+    fn inner(bspc: &dyn Backend, node: u64) -> Result<u64, BspwmError>
+    {
+        //Querying the parent of the root fails
+        match get_node_parent(bspc, node)?
+        {
+            Some(parent) => inner(bspc, parent),
+            // We found the root
+            None => Ok(node)
+        }
+    }
+
+    match all_nodes.first()
+    {
+        None => Ok(None),
+        Some(node) => inner(bspc, *node).map(Some)
+    }
+}
+
+
+/**
+  Turns a failed query into None. bspwm reports queries that match nothing
+  as failures so they can't be told apart from other failed queries
+*/
+fn optional<T>(result: Result<T, BspwmError>) -> Result<Option<T>, BspwmError>
+{
+    match result
+    {
+        Ok(value) => Ok(Some(value)),
+        Err(BspwmError::NonZeroExit(_)) => Ok(None),
+        Err(other) => Err(other)
+    }
+}
+
+/**
+  Reports a failed query about a node as the node missing
+*/
+fn for_node<T>(node: u64, result: Result<T, BspwmError>) -> Result<T, BspwmError>
+{
+    result.map_err(|e| {
+        match e
+        {
+            BspwmError::NonZeroExit(_) => BspwmError::MissingNode(node),
+            other => other
+        }
+    })
+}
+
+
+
+
+////////////////////////////////////////////////////////////////////////////////
+//                      state manipulation commands
+////////////////////////////////////////////////////////////////////////////////
+
+/**
+    Tells BSPWM to resize the specified node
+*/
+pub fn node_resize(bspc: &dyn Backend, node: &str, direction: &ResizeDirection, amount: i32)
+    -> Result<(), BspwmError>
+{
+    let (direction_str, dx, dy) = match *direction
+    {
+        ResizeDirection::Top => ("top", 0, -amount),
+        ResizeDirection::Bottom => ("bottom", 0, amount),
+        ResizeDirection::Left => ("left", 0, -amount),
+        ResizeDirection::Right => ("right", 0, amount)
+    };
+
+    bspc.node(
+        &vec!(
+            &node,
+            "-z",
+            direction_str,
+            &format!("{}", dx),
+            &format!("{}", dy)
+            )
+        ).map(|_| ())
+}
+
+/**
+    Sets the ratio between the first and second node
+*/
+pub fn node_change_ratio(bspc: &dyn Backend, node: &str, new_ratio: f32) -> Result<(), BspwmError>
+{
+    bspc.node(
+        &vec!(
+            &node,
+            "-r",
+            &format!("{}", new_ratio)
+            )
+        ).map(|_| ())
+}
+
+/**
+    Sets the ratios of several nodes, in order. If bspwm fails, the nodes that
+    were already changed get their `previous` ratio back and the error is
+    returned
+*/
+pub fn change_ratios(bspc: &dyn Backend, ratios: &Vec<(u64, f32)>, previous: &Vec<(u64, f64)>)
+    -> Result<(), BspwmError>
+{
+    for (index, &(node, ratio)) in ratios.iter().enumerate()
+    {
+        if let Err(e) = node_change_ratio(bspc, &get_node_name(node), ratio)
+        {
+            restore_ratios(bspc, &ratios[..index], previous);
+            return Err(e)
+        }
+    }
+
+    Ok(())
+}
+
+/**
+    Gives the changed nodes their previous ratio back, in reverse order.
+    Failures are ignored since there is nothing left to fall back to
+*/
+pub fn restore_ratios(bspc: &dyn Backend, changed: &[(u64, f32)], previous: &Vec<(u64, f64)>)
+{
+    for &(node, _) in changed.iter().rev()
+    {
+        if let Some(&(_, ratio)) = previous.iter().find(|&&(id, _)| id == node)
+        {
+            let _ = node_change_ratio(bspc, &get_node_name(node), ratio as f32);
+        }
+    }
+}
+
+/**
+    Balances the children in the specified node
+*/
+pub fn node_balance(bspc: &dyn Backend, node: u64) -> Result<(), BspwmError>
+{
+    let node_name = get_node_name(node);
+
+    bspc.node(
+        &vec!(
+            &node_name,
+            "-B",
+            )
+        ).map(|_| ())
+}
+
+/**
+    Preselects the specified direction of a node so that the next node moved
+    there is inserted on that side
+*/
+pub fn node_preselect(bspc: &dyn Backend, node: u64, direction: &CardinalDirection)
+    -> Result<(), BspwmError>
+{
+    bspc.node(&vec!(&get_node_name(node), "-p", direction.as_str())).map(|_| ())
+}
+
+/**
+    Moves a node to the preselection of the target, or next to the target
+    if it has no preselection
+*/
+pub fn node_transfer(bspc: &dyn Backend, node: u64, target: u64) -> Result<(), BspwmError>
+{
+    bspc.node(&vec!(&get_node_name(node), "-n", &get_node_name(target))).map(|_| ())
+}
+
+/**
+    Sets or clears the hidden flag of a node. Hidden nodes take up no space
+*/
+pub fn node_set_hidden(bspc: &dyn Backend, node: u64, hidden: bool) -> Result<(), BspwmError>
+{
+    let flag = match hidden
+    {
+        true => "hidden=on",
+        false => "hidden=off"
+    };
+
+    bspc.node(&vec!(&get_node_name(node), "-g", flag)).map(|_| ())
+}
+
+/**
+    Swaps two nodes and their subtrees
+*/
+pub fn node_swap(bspc: &dyn Backend, node: u64, target: u64) -> Result<(), BspwmError>
+{
+    bspc.node(&vec!(&get_node_name(node), "-s", &get_node_name(target))).map(|_| ())
+}
+
+/**
+    Sends a node to a desktop and keeps it focused
+*/
+pub fn node_send_to_desktop(bspc: &dyn Backend, node: u64, desktop: u64) -> Result<(), BspwmError>
+{
+    bspc.node(&vec!(&get_node_name(node), "-d", &get_desktop_name(desktop), "--follow")).map(|_| ())
+}
+
+/**
+    Moves a node to the target of a directional move. Nodes are moved next to
+    or swapped with the target node, while desktops get the node sent to them.
+    Does nothing for FocusTarget::None
+*/
+pub fn move_to_target(bspc: &dyn Backend, node: u64, target: &FocusTarget, swap: bool)
+    -> Result<(), BspwmError>
 {
-    match general_query("-N", vec!((flag, selector)))
+    match (target, swap)
     {
-        Ok(val) => Some(val),
-        Err(msg) => {
-            println!("{}", msg);
-            None
-        }
+        (&FocusTarget::Node(target), true) => node_swap(bspc, node, target),
+        (&FocusTarget::Node(target), false) => node_transfer(bspc, node, target),
+        (&FocusTarget::Desktop(desktop), _) => node_send_to_desktop(bspc, node, desktop),
+        (&FocusTarget::None, _) => Ok(())
     }
 }
 
-
-//TODO: Update to use result instead of option
 /**
-    Runs bspc query -N -n $selector
+    Focuses on a specified node
 */
-pub fn node_query(selector: &str) -> Option<Vec<u64>>
+pub fn node_focus(bspc: &dyn Backend, node: u64) -> Result<(), BspwmError>
 {
-    node_query_with_flag(selector, "-n")
+    bspc.node(&vec!("-f", &format!("{}", node))).map(|_| ())
 }
 
-
 /**
-  Runs bspc query -D -d $selector.
-  Err if bspwm returns something unexpected
+    Focuses the specified desktop
 */
-pub fn desktop_query(selector: &str) -> Result<Vec<u64>, String>
+pub fn desktop_focus(bspc: &dyn Backend, desktop: u64) -> Result<(), BspwmError>
 {
-    general_query("-D", vec!(("-d", selector)))
+    bspc.desktop(&vec!("-f", &get_desktop_name(desktop))).map(|_| ())
 }
 
-
 /**
-  Tries to get the root node of the specified desktop
+    Focuses a node or a desktop. Does nothing for FocusTarget::None
 */
-pub fn desktop_top_node_query(desktop: u64) -> Result<Option<u64>, String>
+pub fn focus_target(bspc: &dyn Backend, target: &FocusTarget) -> Result<(), BspwmError>
 {
-    let all_nodes = general_query("-N", vec!(("-d", format!("{}", desktop))))?;
-
-    fn inner(node: u64) -> Result<u64, String>
-    {
-        let new_nodes = match node_query(format!("{}#@parent", node))
-        {
-            Some(nodes) => Ok(nodes),
-            None => Err("No child nodes found for")
-        }?;
-
-
-
-        // We found the root
-        if new_nodes.len() == 0
-        {
-            node
-        }
-        else
-        {
-            inner(new_nodes[0])
-        }
-
-    }
-
-    if all_nodes.len() == 0
-    {
-        Ok(None)
-    }
-    else
+    match *target
     {
-        Ok()
+        FocusTarget::Node(node) => node_focus(bspc, node),
+        FocusTarget::Desktop(desktop) => desktop_focus(bspc, desktop),
+        FocusTarget::None => Ok(())
     }
 }
 
@@ -226,137 +969,142 @@ pub fn desktop_top_node_query(desktop: u64) -> Result<Option<u64>, String>
 
 
 
-
-
 ////////////////////////////////////////////////////////////////////////////////
-//                      state manipulation commands
+//                      High level, safe query functions
 ////////////////////////////////////////////////////////////////////////////////
 
+
+
 /**
-    Tells BSPWM to resize the specified node
+    Returns the first node in a list of nodes. None if the list is empty
+    or if bspwm found no matching node
 */
-pub fn node_resize(node: &str, direction: &ResizeDirection, amount: i32)
+pub fn first_node(list: Result<Vec<u64>, BspwmError>) -> Result<Option<u64>, BspwmError>
 {
-    let (direction_str, dx, dy) = match *direction
-    {
-        ResizeDirection::Top => ("top", 0, -amount),
-        ResizeDirection::Bottom => ("bottom", 0, amount),
-        ResizeDirection::Left => ("left", 0, -amount),
-        ResizeDirection::Right => ("right", 0, amount)
-    };
+    optional(list).map(|list| list.and_then(|mut list| list.pop()))
+}
+
 
-    let program_output = call_program(
-        "bspc",
-        &vec!(
-            "node",
-            &node,
-            "-z",
-            direction_str,
-            &format!("{}", dx),
-            &format!("{}", dy)
-            )
-        );
 
-    println!("Node resize output: {}", program_output.unwrap());
-}
 
 /**
-    Sets the ratio between the first and second node
+    Returns the root node
 */
-pub fn node_change_ratio(node: &str, new_ratio: f32)
+pub fn get_root_node(bspc: &dyn Backend) -> Result<u64, BspwmError>
 {
-    let _ = call_program(
-        "bspc",
-        &vec!(
-            "node",
-            &node,
-            "-r",
-            &format!("{}", new_ratio)
-            )
-        );
-
-    //println!("{}", program_output.unwrap());
+    first_node(node_query(bspc, "@/"))?
+        .ok_or(BspwmError::Unparsable("bspwm returned no root node".to_string()))
 }
 
-/**
-    Balances the children in the specified node
-*/
-pub fn node_balance(node: u64)
-{
-    let node_name = get_node_name(node);
 
-    call_program(
-        "bspc",
-        &vec!(
-            "node",
-            &node_name,
-            "-B",
-            )
-        ).unwrap();
-}
+
+
 
 /**
-    Focuses on a specified node
+  Returns all the nodes that currently exist
 */
-pub fn node_focus(node: u64)
+pub fn get_all_nodes(bspc: &dyn Backend) -> Result<Vec<u64>, BspwmError>
 {
-    call_program("bspc", &vec!("node", "-f", &format!("{}", node))).unwrap();
+    node_query_with_flag(bspc, "",  "")
 }
 
 
 
 
 
+/**
+  Checks if a node is a descendant of the parent node in the tree
+*/
+pub fn is_node_descendant(parent: &Node, child: u64) -> bool
+{
+    get_node_descendants(parent).contains(&child)
+}
 
 
-////////////////////////////////////////////////////////////////////////////////
-//                      High level, safe query functions
-////////////////////////////////////////////////////////////////////////////////
 
 
 
 /**
-    Returns the first node in a list of nodes. Performs 2 uwnwraps
-    so it will crash if the list is empty or None
+  Checks wether or not a node exists
 */
-pub fn first_node(list: Option<Vec<u64>>) -> Option<u64>
+pub fn get_node_exists(bspc: &dyn Backend, id: u64) -> Result<bool, BspwmError>
 {
-    list.unwrap().pop()
+    get_all_nodes(bspc).map(|nodes| nodes.contains(&id))
 }
 
 
 
 
 /**
-    Returns the root node
+  Returns the parent of a node, None if it is the root of its desktop
 */
-pub fn get_root_node() -> u64
+pub fn get_node_parent(bspc: &dyn Backend, node: u64) -> Result<Option<u64>, BspwmError>
 {
-    first_node(node_query("@/")).unwrap()
+    first_node(general_query(bspc, "-N", vec!(("-n", &format!("{}#@parent", get_node_name(node))))))
 }
 
+/**
+  Returns the id of the desktop that the node is on
+*/
+pub fn get_node_desktop(bspc: &dyn Backend, node: u64) -> Result<u64, BspwmError>
+{
+    for_node(node, general_query(bspc, "-D", vec!(("-n", &get_node_name(node)))))?
+        .first().cloned()
+        .ok_or(BspwmError::MissingNode(node))
+}
 
+/**
+  Returns the name of the desktop that the node is on
+*/
+pub fn get_node_desktop_name(bspc: &dyn Backend, node: u64) -> Result<String, BspwmError>
+{
+    name_query(bspc, "-D", node)
+}
 
+/**
+  Returns the id of the monitor that the node is on
+*/
+pub fn get_node_monitor(bspc: &dyn Backend, node: u64) -> Result<u64, BspwmError>
+{
+    for_node(node, general_query(bspc, "-M", vec!(("-n", &get_node_name(node)))))?
+        .first().cloned()
+        .ok_or(BspwmError::MissingNode(node))
+}
 
+/**
+  Returns the name of the monitor that the node is on
+*/
+pub fn get_node_monitor_name(bspc: &dyn Backend, node: u64) -> Result<String, BspwmError>
+{
+    name_query(bspc, "-M", node)
+}
 
 /**
-  Returns all the nodes that currently exist
+  Returns bspwm's window_gap setting on the desktop of a node
 */
-pub fn get_all_nodes() -> Vec<u64>
+pub fn get_node_window_gap(bspc: &dyn Backend, node: u64) -> Result<u64, BspwmError>
 {
-    node_query_with_flag("",  "").unwrap()
+    let desktop = get_node_desktop(bspc, node)?;
+    let gap = bspc.config(&vec!("-d", &get_desktop_name(desktop), "window_gap"))?;
+
+    match gap.trim().parse::<i64>()
+    {
+        Ok(gap) => Ok(::std::cmp::max(gap, 0) as u64),
+        Err(_) => Err(BspwmError::Unparsable(format!("window_gap is {}", gap)))
+    }
 }
 
 
 
 
 
+
 /**
-  Querys bspc to check if a node exists
+    Querys bspc for the currently focused node. None if no node is focused
 */
-pub fn is_node_descendant(parent: &json::Object, child: u64) -> bool
+pub fn get_focused_node(bspc: &dyn Backend) -> Result<Option<u64>, BspwmError>
 {
-    get_node_descendants(parent).contains(&child)
+    first_node(node_query(bspc, ""))
 }
 
 
@@ -364,35 +1112,52 @@ pub fn is_node_descendant(parent: &json::Object, child: u64) -> bool
 
 
 /**
-  Checks wether or not a node exists
+    Querys bspc for the currently focused desktop
 */
-pub fn get_node_exists(id: u64) -> bool
+pub fn get_focused_desktop(bspc: &dyn Backend) -> Result<u64, BspwmError>
 {
-    get_all_nodes().contains(&id)
+    first_node(desktop_query(bspc, ""))?
+        .ok_or(BspwmError::Unparsable("bspwm returned no focused desktop".to_string()))
 }
 
-
-
-
 /**
-    Querys bspc for the currently focused node
+    Querys bspc for the currently focused monitor
 */
-pub fn get_focused_node() -> Option<u64>
+pub fn get_focused_monitor(bspc: &dyn Backend) -> Result<u64, BspwmError>
 {
-    //node_query("").unwrap().pop().unwrap()
-    first_node(node_query(""))
+    first_node(monitor_query(bspc, ""))?
+        .ok_or(BspwmError::Unparsable("bspwm returned no focused monitor".to_string()))
 }
 
+/**
+  Returns the monitor next to a monitor, None if there is no monitor in
+  that direction
+ */
+pub fn get_neighbouring_monitor(bspc: &dyn Backend, monitor: u64, direction: &CardinalDirection)
+    -> Result<Option<u64>, BspwmError>
+{
+    let query = format!("{}#{}", get_monitor_name(monitor), direction.as_str());
+    first_node(monitor_query(bspc, &query))
+}
 
-
-
+/**
+  Returns the desktop that is shown on a monitor
+ */
+pub fn get_monitor_desktop(bspc: &dyn Backend, monitor: u64) -> Result<u64, BspwmError>
+{
+    let query = format!("{}:focused", get_monitor_name(monitor));
+    first_node(desktop_query(bspc, &query))?
+        .ok_or(BspwmError::Unparsable(format!("Monitor {} shows no desktop", monitor)))
+}
 
 /**
-  Returns a list of all nodes on the specified desktop 
+  Returns a list of all nodes on the specified desktop, which is empty if
+  the desktop is
  */
-pub fn get_nodes_on_desktop(desktop: u64) -> Vec<u64>
+pub fn get_nodes_on_desktop(bspc: &dyn Backend, desktop: u64) -> Result<Vec<u64>, BspwmError>
 {
-    node_query_with_flag(&get_desktop_name(desktop), "-d").unwrap()
+    optional(node_query_with_flag(bspc, &get_desktop_name(desktop), "-d"))
+        .map(|nodes| nodes.unwrap_or(vec!()))
 }
 
 
@@ -400,12 +1165,14 @@ pub fn get_nodes_on_desktop(desktop: u64) -> Vec<u64>
 
 
 /**
-  Returns the neighbour of a specified node
+  Returns the neighbour of a specified node, None if there is no node in
+  that direction
  */
-pub fn get_neighbouring_node(node: u64, direction: CardinalDirection) -> Option<u64>
+pub fn get_neighbouring_node(bspc: &dyn Backend, node: u64, direction: CardinalDirection)
+    -> Result<Option<u64>, BspwmError>
 {
     let query = format!("{}#{}", get_node_name(node), direction.as_str());
-    node_query(&query).unwrap().pop()
+    first_node(node_query(bspc, &query))
 }
 
 
@@ -413,15 +1180,16 @@ pub fn get_neighbouring_node(node: u64, direction: CardinalDirection) -> Option<
 
 
 /**
-    Gets the subtree of node as a JSON object
+    Gets the subtree of node. Err if bspc fails or if the output does not
+    match the tree model
 */
-pub fn get_node_json(node: u64) -> json::Object
+pub fn get_node_tree(bspc: &dyn Backend, node: u64) -> Result<Node, BspwmError>
 {
     let node_str = format!("{}", node);
 
-    let str_json = call_program("bspc", &vec!("query", "-T", "-n", &node_str)).unwrap();
+    let str_json = for_node(node, bspc.query(&vec!("-T", "-n", &node_str)))?;
 
-    json::Json::from_str(&str_json).unwrap().as_object().unwrap().clone()
+    Node::parse(&str_json)
 }
 
 
@@ -431,39 +1199,23 @@ pub fn get_node_json(node: u64) -> json::Object
 /**
     Returns the split type of a node.
 */
-pub fn get_node_split_direction(node_json: &json::Object) -> SplitDirection
+pub fn get_node_split_direction(node: &Node) -> SplitDirection
 {
-    match node_json.get("splitType").unwrap().as_string().unwrap().as_ref()
-    {
-        "horizontal" => SplitDirection::Horizontal,
-        "vertical" => SplitDirection::Vertical,
-        _ => panic!("Unknown splitType value")
-    }
+    node.split_type.clone()
 }
 
 
 
 
 /**
-    Returns all children of a specific node
+    Returns both children of a specific node, or None if it is a leaf
 */
-pub fn get_node_children(node_json: &json::Object) -> Option<(json::Object, json::Object)>
+pub fn get_node_children(node: &Node) -> Option<(&Node, &Node)>
 {
-    //Try getting the first child field. This should always exist as long as the
-    //node is valid
-    let first_child = node_json.get("firstChild").unwrap().as_object();
-
-    //Second child can not be None if First childe is Some because the whole
-    //tree is a full binary tree
-    match first_child
+    //The whole tree is a full binary tree so either both children exist or none
+    match node.children
     {
-        Some(child) =>
-            Some(
-                (
-                    child.clone(), 
-                    node_json.get("secondChild").unwrap().as_object().unwrap().clone()
-                )
-            ),
+        Some(ref children) => Some((&children.0, &children.1)),
         None => None
     }
 }
@@ -477,20 +1229,20 @@ pub fn get_node_children(node_json: &json::Object) -> Option<(json::Object, json
   It will traverse the tree until it either finds a leaf node, or a node that 
   is split the oposite direction of the stack
 */
-pub fn find_target_stack(root: &json::Object, direction: &SplitDirection) -> Vec<u64>
+pub fn find_target_stack(root: &Node, direction: &SplitDirection) -> Vec<u64>
 {
     //Check if the node has children
     let node_children = get_node_children(root);
 
-    if node_children == None || get_node_split_direction(&root) != *direction
+    if node_children.is_none() || get_node_split_direction(root) != *direction
     {
-        vec!(get_node_id(&root))
+        vec!(get_node_id(root))
     }
     else
     {
         let (first_child, second_child) = node_children.unwrap();
-        let mut result = find_target_stack(&first_child, direction);
-        result.append(&mut find_target_stack(&second_child, direction));
+        let mut result = find_target_stack(first_child, direction);
+        result.append(&mut find_target_stack(second_child, direction));
         
         result
     }
@@ -500,11 +1252,34 @@ pub fn find_target_stack(root: &json::Object, direction: &SplitDirection) -> Vec
 
 
 /**
-    Returns the ID of a given node from its json representation
+  Returns the members of a stack in order. Like `find_target_stack`, but
+  the roots of nested stacks are members too
+*/
+pub fn find_stack_members<'a>(node: &'a Node, direction: &SplitDirection, nested_roots: &Vec<u64>)
+    -> Vec<&'a Node>
+{
+    match get_node_children(node)
+    {
+        Some((first, second)) if !is_stack_member(node, direction, nested_roots) =>
+        {
+            let mut result = find_stack_members(first, direction, nested_roots);
+            result.append(&mut find_stack_members(second, direction, nested_roots));
+
+            result
+        }
+        _ => vec!(node)
+    }
+}
+
+
+
+
+/**
+    Returns the ID of a given node
 */
-pub fn get_node_id(node_json: &json::Object) -> u64
+pub fn get_node_id(node: &Node) -> u64
 {
-    node_json.get("id").unwrap().as_u64().unwrap()
+    node.id
 }
 
 
@@ -533,28 +1308,39 @@ pub fn get_desktop_name(id: u64) -> String
 
 
 
+/**
+  Returns the a string representation of the ID of a monitor that can be interpreted by bspc
+  (0x...)
+*/
+pub fn get_monitor_name(id: u64) -> String
+{
+    format!("0x{:X}", id)
+}
+
+
+
 
 /**
   Returns the list of directions you have to take from a node
   to a descendant. The list is in a reverse order so in order to walk the
   path to the bottom node, you should pop the resulting vector
 */
-pub fn find_path_to_node(root: &json::Object, target: u64) -> Option<Vec<Children>>
+pub fn find_path_to_node(root: &Node, target: u64) -> Option<Vec<Children>>
 {
     let node_children = get_node_children(root);
     if get_node_id(root) == target
     {
         return Some(vec!())
     }
-    else if node_children == None
+    else if node_children.is_none()
     {
         return None
     }
     else
     {
         let (first_child, second_child) = node_children.unwrap();
-        let direction_to_first = find_path_to_node(&first_child, target);
-        let direction_to_second = find_path_to_node(&second_child, target);
+        let direction_to_first = find_path_to_node(first_child, target);
+        let direction_to_second = find_path_to_node(second_child, target);
 
         match (direction_to_first, direction_to_second)
         {
@@ -578,9 +1364,9 @@ pub fn find_path_to_node(root: &json::Object, target: u64) -> Option<Vec<Childre
 /**
  Returns all the descendant nodes of a specified node
 */
-pub fn get_node_descendants(root: &json::Object) -> Vec<u64>
+pub fn get_node_descendants(root: &Node) -> Vec<u64>
 {
-    fn tail_recursion_helper(root: &json::Object, buffer: &mut Vec<u64>)
+    fn tail_recursion_helper(root: &Node, buffer: &mut Vec<u64>)
     {
         buffer.push(get_node_id(root));
         match get_node_children(root)
@@ -590,8 +1376,8 @@ pub fn get_node_descendants(root: &json::Object) -> Vec<u64>
             {
                 let (first, second) = children;
 
-                tail_recursion_helper(&first, buffer);
-                tail_recursion_helper(&second, buffer);
+                tail_recursion_helper(first, buffer);
+                tail_recursion_helper(second, buffer);
             }
         }
     }
@@ -610,7 +1396,7 @@ pub fn get_node_descendants(root: &json::Object) -> Vec<u64>
 /**
     Counts the amount of descendants that a node has
 */
-pub fn count_node_descendant_leaves(root: &json::Object) -> u64
+pub fn count_node_descendant_leaves(root: &Node) -> u64
 {
     get_node_descendant_leaves(root).len() as u64
 }
@@ -622,7 +1408,7 @@ pub fn count_node_descendant_leaves(root: &json::Object) -> u64
 /**
   Returns all the leaf nodes that are descendants of root
 */
-pub fn get_node_descendant_leaves(root: &json::Object) -> Vec<u64>
+pub fn get_node_descendant_leaves(root: &Node) -> Vec<u64>
 {
     let children = get_node_children(root);
 
@@ -631,8 +1417,8 @@ pub fn get_node_descendant_leaves(root: &json::Object) -> Vec<u64>
         None => vec!(get_node_id(root)),
         Some((first, second)) =>
         {
-            let mut second_result = get_node_descendant_leaves(&second);
-            let mut first_result = get_node_descendant_leaves(&first);
+            let mut second_result = get_node_descendant_leaves(second);
+            let mut first_result = get_node_descendant_leaves(first);
             first_result.append(&mut second_result);
 
             first_result
@@ -643,54 +1429,159 @@ pub fn get_node_descendant_leaves(root: &json::Object) -> Vec<u64>
 
 
 
-pub fn focus_node_by_path(
-        node_json: &json::Object,
-        mut remaining_path: Vec<Children>,
-        resize_directions: &(ResizeDirection, ResizeDirection)
-    )
+/**
+  Returns the split ratios of root and all the internal nodes below it
+*/
+pub fn get_node_ratios(root: &Node) -> Vec<(u64, f64)>
+{
+    match get_node_children(root)
+    {
+        Some((first, second)) => {
+            let mut ratios = vec!((get_node_id(root), root.split_ratio));
+            ratios.append(&mut get_node_ratios(first));
+            ratios.append(&mut get_node_ratios(second));
+            ratios
+        }
+        None => vec!()
+    }
+}
+
+/**
+  Returns the leaf nodes that are descendants of root, in order
+*/
+pub fn get_node_descendant_leaf_nodes(root: &Node) -> Vec<&Node>
+{
+    match get_node_children(root)
+    {
+        None => vec!(root),
+        Some((first, second)) =>
+        {
+            let mut result = get_node_descendant_leaf_nodes(first);
+            result.append(&mut get_node_descendant_leaf_nodes(second));
+
+            result
+        }
+    }
+}
+
+
+
+
+/**
+  How large the collapsed windows of a stack should be
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct CollapsedSize
 {
-    let current_intersection = remaining_path.pop();
+    /// The visible size of each collapsed window in pixels
+    pub window_size: u64,
+    /// bspwm's window_gap on the desktop of the stack
+    pub window_gap: u64
+}
 
-    //Find which node should be traversed and which node should be balanced
-    let (should_balance_first, traverse_node, balance_node) = match current_intersection
+impl CollapsedSize
+{
+    /**
+      The number of pixels that a subtree needs along the split direction so
+      that every window in it is `window_size` pixels large
+    */
+    pub fn subtree_size(&self, node: &Node, direction: &SplitDirection) -> u64
     {
-        None => {return},
-        Some(val) => 
+        match get_node_children(node)
         {
-            match val
+            Some((first, second)) =>
             {
-                Children::First =>
-                {
-                    let (first_child, second_child) = get_node_children(node_json).unwrap();
-                    (false, first_child, second_child)
-                },
-                Children::Second =>
+                let first_size = self.subtree_size(first, direction);
+                let second_size = self.subtree_size(second, direction);
+
+                match get_node_split_direction(node) == *direction
                 {
-                    let (first_child, second_child) = get_node_children(node_json).unwrap();
-                    (true, second_child, first_child)
+                    true => first_size + second_size,
+                    false => ::std::cmp::max(first_size, second_size)
                 }
             }
+            None =>
+            {
+                let border_width = node.client.as_ref().map(|client| client.border_width).unwrap_or(0);
+
+                self.window_size + self.window_gap + 2 * border_width
+            }
         }
-    };
+    }
+}
 
-    //Calculate the ratio that we need to change the current node to
-    let balance_node_size = 0.05 * count_node_descendant_leaves(&balance_node) as f32;
+/**
+  Smallest ratio that is used when collapsing, bspwm rejects 0 and 1
+*/
+const MIN_SPLIT_RATIO: f64 = 0.01;
 
-    let ratio = match should_balance_first
+/**
+  Returns the split ratio that gives the first child `size` pixels out of
+  `extent`. bspwm rounds the size of the first child down
+*/
+pub fn pixel_ratio(size: u64, extent: u64) -> f64
+{
+    if extent == 0
     {
-        true => balance_node_size,
-        false => 1. - balance_node_size
-    };
+        return 0.5
+    }
+
+    let ratio = (size as f64 + 0.5) / extent as f64;
+
+    ratio.max(MIN_SPLIT_RATIO).min(1. - MIN_SPLIT_RATIO)
+}
+
+/**
+  Splits a rectangle the same way bspwm does, giving the first child `first_size`
+  pixels along the split direction
+*/
+pub fn split_rectangle(rectangle: &Rectangle, direction: &SplitDirection, first_size: u64)
+    -> (Rectangle, Rectangle)
+{
+    let mut first = rectangle.clone();
+    let mut second = rectangle.clone();
+
+    match *direction
+    {
+        SplitDirection::Horizontal =>
+        {
+            let first_size = ::std::cmp::min(first_size, rectangle.height);
+            first.height = first_size;
+            second.y += first_size as i64;
+            second.height -= first_size;
+        }
+        SplitDirection::Vertical =>
+        {
+            let first_size = ::std::cmp::min(first_size, rectangle.width);
+            first.width = first_size;
+            second.x += first_size as i64;
+            second.width -= first_size;
+        }
+    }
 
-    //Get the names of the nodes we want to change
-    let current_node_name = get_node_name(get_node_id(node_json));
+    (first, second)
+}
 
-    //Apply the transformations
-    node_change_ratio(&current_node_name, ratio);
-    node_balance(get_node_id(&balance_node));
+/**
+  Returns the size of a rectangle along a split direction
+*/
+pub fn rectangle_extent(rectangle: &Rectangle, direction: &SplitDirection) -> u64
+{
+    match *direction
+    {
+        SplitDirection::Horizontal => rectangle.height,
+        SplitDirection::Vertical => rectangle.width
+    }
+}
 
-    //Dig deeper
-    focus_node_by_path(&traverse_node, remaining_path, resize_directions);
+/**
+  Returns true if the node is a single member of a stack split along `direction`
+  rather than part of its layout. Nodes split the other way and the roots of
+  nested stacks are members
+*/
+pub fn is_stack_member(node: &Node, direction: &SplitDirection, nested_roots: &Vec<u64>) -> bool
+{
+    get_node_split_direction(node) != *direction || nested_roots.contains(&get_node_id(node))
 }
 
 
@@ -713,12 +1604,28 @@ mod tests
         , count_node_descendant_leaves
         , get_node_descendants
         , is_node_descendant
+        , Node
+        , Rectangle
+        , ClientState
+        , Event
+        , CollapsedSize
+        , pixel_ratio
+        , split_rectangle
+        , BspwmError
     };
 
+    use backend::BspcProgram;
+
     use std::io::prelude::*;
     use std::fs::File;
 
-    use rustc_serialize::json;
+    fn load_sample_tree() -> String
+    {
+        let mut f = File::open("sample_tree.json").unwrap();
+        let mut s = String::new();
+        f.read_to_string(&mut s).unwrap();
+        s
+    }
 
 
     #[test]
@@ -726,9 +1633,9 @@ mod tests
     {
         //Ensure exactly one focused window is returned
         {
-            let query_result = node_query("");
+            let query_result = node_query(&BspcProgram, "");
 
-            assert!(query_result.is_some());
+            assert!(query_result.is_ok());
             
             println!("{:?}", query_result.clone().unwrap());
             assert!(query_result.unwrap().len() == 1);
@@ -741,13 +1648,7 @@ mod tests
     fn tree_traversal_test()
     {
         //Load some sample json from a file
-        let mut f = File::open("sample_tree.json").unwrap();
-        let mut s = String::new();
-        f.read_to_string(&mut s).unwrap();
-
-
-        let string_json = json::Json::from_str(&s).unwrap();
-        let data = string_json.as_object().unwrap();
+        let data = Node::parse(&load_sample_tree()).unwrap();
 
         assert!(get_node_children(&data).is_some());
         assert!(get_node_id(&get_node_children(&data).unwrap().0) == 29475921);
@@ -792,4 +1693,97 @@ mod tests
         //Check if the child is a descendant of its parent
         assert_eq!(is_node_descendant(&get_node_children(&data).unwrap().0, 4194621), false);
     }
+
+    #[test]
+    fn tree_model_test()
+    {
+        let data = Node::parse(&load_sample_tree()).unwrap();
+
+        assert_eq!(data.split_type, SplitDirection::Vertical);
+        assert_eq!(data.split_ratio, 0.5);
+        assert_eq!(data.birth_rotation, Some(0));
+        assert_eq!(data.presel, None);
+        assert_eq!(data.constraints, None);
+        assert!(data.client.is_none());
+        assert_eq!(data.rectangle, Rectangle{x: 1287, y: 1087, width: 1886, height: 1073});
+
+        let (first, _) = get_node_children(&data).unwrap();
+        let client = first.client.clone().unwrap();
+        assert_eq!(client.class_name, "Xfce4-terminal");
+        assert_eq!(client.border_width, 1);
+        assert_eq!(client.state, ClientState::Tiled);
+    }
+
+    #[test]
+    fn collapsed_size_test()
+    {
+        let root = Node::parse(&load_sample_tree()).unwrap();
+        let size = CollapsedSize {window_size: 20, window_gap: 6};
+
+        //Each window needs its size, the gap and two borders
+        let (_, right) = get_node_children(&root).unwrap();
+        assert_eq!(size.subtree_size(right, &SplitDirection::Horizontal), 4 * 28);
+        assert_eq!(size.subtree_size(right, &SplitDirection::Vertical), 2 * 28);
+
+        //bspwm rounds down, the ratio has to make up for that
+        let ratio = pixel_ratio(28, 1073);
+        assert_eq!((1073. * ratio) as u64, 28);
+
+        //Ratios never reach 0 or 1 no matter how many windows there are
+        assert!(pixel_ratio(2000, 1073) < 1.);
+        assert!(pixel_ratio(0, 1073) > 0.);
+
+        let (first, second) = split_rectangle(&root.rectangle, &SplitDirection::Vertical, 900);
+        assert_eq!(first, Rectangle{x: 1287, y: 1087, width: 900, height: 1073});
+        assert_eq!(second, Rectangle{x: 2187, y: 1087, width: 986, height: 1073});
+    }
+
+    #[test]
+    fn tree_schema_mismatch_test()
+    {
+        let sample = load_sample_tree();
+
+        //Fields that bspwm added should be reported
+        let added = sample.replacen("\"locked\"", "\"frobnicated\":true,\"locked\"", 1);
+        assert_eq!(
+                Node::parse(&added),
+                Err(BspwmError::SchemaMismatch("Unexpected field 'frobnicated' in node".to_string()))
+            );
+
+        //As should fields that were dropped
+        let dropped = sample.replacen("\"sticky\":false,", "", 1);
+        assert_eq!(
+                Node::parse(&dropped),
+                Err(BspwmError::SchemaMismatch("Missing field 'sticky' in node".to_string()))
+            );
+
+        match Node::parse("not json")
+        {
+            Err(BspwmError::Unparsable(_)) => {},
+            other => panic!("Expected Unparsable, got {:?}", other)
+        }
+        assert!(Node::parse(&sample.replace("\"vertical\"", "\"diagonal\"")).is_err());
+    }
+
+    #[test]
+    fn event_parse_test()
+    {
+        assert_eq!(
+                Event::parse("node_focus 0x00200002 0x00200003 0x01C2891A"),
+                Some(Event::NodeFocus(29526298))
+            );
+        assert_eq!(Event::parse("node_focus 0x00200002"), None);
+        assert_eq!(Event::parse("node_remove 0x00200002 0x00200003 0x01C2891A"), Some(Event::NodeRemove(0x200003)));
+        assert_eq!(
+                Event::parse("node_add 0x00200002 0x00200003 0x01C2891A 0x01C2C3F3"),
+                Some(Event::NodeAdd(0x200003))
+            );
+        assert_eq!(
+                Event::parse("node_transfer 0x00200002 0x00200003 0x01C2891A 0x00200002 0x00200004 0x01C2C3F3"),
+                Some(Event::NodeTransfer(0x200003, 0x200004))
+            );
+        assert_eq!(Event::parse("node_transfer 0x00200002 0x00200003 0x01C2891A"), None);
+        assert_eq!(Event::parse("desktop_focus 0x00200002 0x00200003"), Some(Event::DesktopFocus));
+        assert_eq!(Event::parse("node_geometry 0x00200002 0x00200003 0x01C2891A 1x1+0+0"), None);
+    }
 }
@@ -9,17 +9,35 @@ extern crate typed_messages;
 mod bspwm;
 mod subprogram;
 mod messages;
-mod swap;
+mod backend;
+mod transport;
+mod config;
+mod tabs;
 
-use messages::{Command, CommandResponse};
+use messages::{Command, CommandResponse, StackInfo};
 
-use typed_messages::connect_send_read;
+use rustc_serialize::json;
+
+use transport::Transport;
+use config::{Config, StackMode, STACK_MODES};
+use tabs::{TabBar, TabFormat};
 
 use clap::{App, Arg, SubCommand};
 
 use std::time::Duration;
+use std::process;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::os::unix::net::UnixStream;
 
-const TIMEOUT_SECONDS: u64 = 1;
+/**
+    Where rspwm can be reached and how long to wait for it
+ */
+struct Server
+{
+    transport: Transport,
+    timeout: Duration
+}
 
 
 fn direction_from_string(string: &str) -> Result<bspwm::CardinalDirection, String>
@@ -37,20 +55,27 @@ fn direction_from_string(string: &str) -> Result<bspwm::CardinalDirection, Strin
 /**
     Tries to send a message to the server
  */
-fn try_send_message(command: Command) -> Option<CommandResponse>
+fn try_send_message(server: &Server, command: Command) -> Option<CommandResponse>
 {
-    let timeout = Some(Duration::new(TIMEOUT_SECONDS, 0));
-
-    match connect_send_read::<_, CommandResponse>("localhost", 9232, command, timeout)
+    match server.transport.send::<_, CommandResponse>(command, Some(server.timeout))
     {
         Ok(result) => Some(result),
         Err(e) => {
-            println!("Failed to send message. Error: {:?}", e);
+            println!("Failed to send message. Error: {}", e);
             None
         }
     }
 }
 
+/**
+    Reports a command that bspwm refused and exits with a non-zero status
+ */
+fn exit_bspwm_failed(error: bspwm::BspwmError) -> !
+{
+    println!("bspwm failed: {}", error);
+    process::exit(1)
+}
+
 /**
     Handles responses from the server where the expected output is Command::Done
  */
@@ -61,6 +86,7 @@ fn handle_done_fail_response(response: Option<CommandResponse>, ok_msg: &str)
         Some(CommandResponse::Done) => {
             println!("{}", ok_msg);
         },
+        Some(CommandResponse::Failed(error)) => exit_bspwm_failed(error),
         Some(other) => {
             println!("Server replied unexpectedly. Expected Done, got {:?}", other);
         }
@@ -68,34 +94,282 @@ fn handle_done_fail_response(response: Option<CommandResponse>, ok_msg: &str)
     }
 }
 
-fn do_create_stack()
+/**
+    Parses a stack mode, exiting with an error if it is invalid
+ */
+fn parse_stack_mode(mode: Option<&str>) -> StackMode
+{
+    match mode.and_then(StackMode::from_str)
+    {
+        Some(mode) => mode,
+        None => {
+            println!("Expected a mode {{{}}}", STACK_MODES);
+            process::exit(1)
+        }
+    }
+}
+
+fn do_create_stack(server: &Server, mode: Option<&str>)
 {
-    let response = try_send_message(Command::CreateStack);
+    let mode = mode.map(|mode| parse_stack_mode(Some(mode)));
 
-    handle_done_fail_response(response, "Stack created successfully");
+    match try_send_message(server, Command::CreateStack(mode))
+    {
+        Some(CommandResponse::StackExists) => {
+            println!("The focused node is already a stack");
+            process::exit(1)
+        }
+        response => handle_done_fail_response(response, "Stack created successfully")
+    }
 }
 
-fn do_remove_focused_stack()
+fn do_remove_focused_stack(server: &Server)
 {
-    let response = try_send_message(Command::RemoveFocused);
+    let response = try_send_message(server, Command::RemoveFocused);
 
     handle_done_fail_response(response, "Stack removed")
 }
 
-fn do_focus_current()
+fn do_focus_current(server: &Server)
 {
-    let response = try_send_message(Command::FocusCurrent);
+    let response = try_send_message(server, Command::FocusCurrent);
 
     handle_done_fail_response(response, "Current is focused")
 }
 
-fn do_update_stacks()
+fn do_update_stacks(server: &Server)
 {
-    let response = try_send_message(Command::UpdateStacks);
+    let response = try_send_message(server, Command::UpdateStacks);
 
     handle_done_fail_response(response, "Stacks updated")
 }
 
+/**
+    Handles responses to commands that change stack membership
+ */
+fn handle_membership_response(response: Option<CommandResponse>, ok_msg: &str)
+{
+    match response
+    {
+        Some(CommandResponse::Done) => println!("{}", ok_msg),
+        Some(CommandResponse::NoStackExists) => println!("The focused window is not in a stack"),
+        Some(CommandResponse::NoSuchNode) => println!("No such window"),
+        Some(CommandResponse::Failed(error)) => exit_bspwm_failed(error),
+        Some(other) => {
+            println!("Server replied unexpectedly. Expected Done, got {:?}", other);
+        }
+        None => {}
+    }
+}
+
+fn do_add_to_stack(server: &Server, parameters: Vec<&str>)
+{
+    let node = match parameters.get(0)
+    {
+        Some(node) => node.to_string(),
+        None => {
+            println!("Expected a node to add");
+            return
+        }
+    };
+
+    let position = match parameters.get(1).map(|position| position.parse::<usize>())
+    {
+        None => None,
+        Some(Ok(position)) => Some(position),
+        Some(Err(_)) => {
+            println!("The position must be a number");
+            return
+        }
+    };
+
+    let response = try_send_message(server, Command::AddToStack(node, position));
+
+    handle_membership_response(response, "Window added")
+}
+
+fn do_eject(server: &Server)
+{
+    let response = try_send_message(server, Command::EjectFocused);
+
+    handle_membership_response(response, "Window ejected")
+}
+
+fn do_set_stack_mode(server: &Server, mode: Option<&str>)
+{
+    let mode = parse_stack_mode(mode);
+
+    let response = try_send_message(server, Command::SetStackMode(mode));
+
+    handle_membership_response(response, "Stack mode changed")
+}
+
+/**
+    Moves within the focused stack. Exits with a non-zero status if the focus
+    did not change so that scripts can fall back to `bspc node -f <direction>`
+ */
+fn do_move(server: &Server, direction: Option<&str>)
+{
+    let direction = match direction.map(direction_from_string)
+    {
+        Some(Ok(direction)) => direction,
+        Some(Err(other)) => {
+            println!("Unknown direction: {}", other);
+            process::exit(1)
+        }
+        None => {
+            println!("Expected a direction {{north, south, east, west}}");
+            process::exit(1)
+        }
+    };
+
+    match try_send_message(server, Command::Move(direction))
+    {
+        Some(CommandResponse::Done) => {},
+        Some(CommandResponse::EndOfStack) => {
+            println!("Reached the end of the stack");
+            process::exit(1)
+        },
+        Some(CommandResponse::UseBspwmFocus) | Some(CommandResponse::NoStackExists) => {
+            process::exit(1)
+        },
+        Some(CommandResponse::Failed(error)) => exit_bspwm_failed(error),
+        Some(other) => {
+            println!("Server replied unexpectedly. Expected Done, got {:?}", other);
+            process::exit(1)
+        }
+        None => process::exit(1)
+    }
+}
+
+/**
+    Formats a stack for humans, one line describing the stack followed by
+    one line per member with the leaves of the member. The member containing
+    the expanded leaf is marked by a *
+ */
+fn format_stack_info(info: &StackInfo) -> String
+{
+    let describe = |name: &Option<String>, id: Option<u64>| {
+        match (name, id)
+        {
+            (&Some(ref name), Some(id)) => format!("{} (0x{:08X})", name, id),
+            (_, Some(id)) => format!("0x{:08X}", id),
+            _ => "unknown".to_string()
+        }
+    };
+
+    let mut result = format!(
+            "0x{:08X} on desktop {}, monitor {}, {:?}\n",
+            info.root,
+            describe(&info.desktop_name, info.desktop),
+            describe(&info.monitor_name, info.monitor),
+            info.split
+        );
+
+    for member in &info.members
+    {
+        let marker = match info.expanded.map(|expanded| member.contains(&expanded)).unwrap_or(false)
+        {
+            true => "*",
+            false => " "
+        };
+        let leaves = member.iter().map(|leaf| format!("0x{:08X}", leaf)).collect::<Vec<_>>();
+        result.push_str(&format!("  {} {}\n", marker, leaves.join(" ")));
+    }
+
+    result
+}
+
+fn do_list_stacks(server: &Server, as_json: bool)
+{
+    match try_send_message(server, Command::ListStacks)
+    {
+        Some(CommandResponse::Stacks(infos)) => {
+            if as_json
+            {
+                println!("{}", json::encode(&infos).unwrap());
+            }
+            else
+            {
+                for info in &infos
+                {
+                    print!("{}", format_stack_info(info));
+                }
+            }
+        },
+        Some(CommandResponse::Failed(error)) => exit_bspwm_failed(error),
+        Some(other) => {
+            println!("Server replied unexpectedly. Expected Stacks, got {:?}", other);
+        }
+        None => {}
+    }
+}
+
+
+/**
+    Prints a line for every change of the stack tabs until rspwm exits
+ */
+fn do_follow_tabs(format: &str, monitor: Option<&str>)
+{
+    let format = match TabFormat::from_str(format)
+    {
+        Some(format) => format,
+        None => {
+            println!("Unknown format: {}", format);
+            process::exit(1)
+        }
+    };
+
+    let path = match tabs::feed_socket_path()
+    {
+        Some(path) => path,
+        None => {
+            println!("XDG_RUNTIME_DIR is not set");
+            process::exit(1)
+        }
+    };
+
+    let stream = match UnixStream::connect(&path)
+    {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("Failed to connect to {}. Error: {}", path.display(), e);
+            process::exit(1)
+        }
+    };
+
+    let stdout = io::stdout();
+    for line in BufReader::new(stream).lines()
+    {
+        let bars: Vec<TabBar> = match line.map(|line| json::decode(&line))
+        {
+            Ok(Ok(bars)) => bars,
+            Ok(Err(e)) => {
+                println!("Failed to decode tab bars. Error: {}", e);
+                continue
+            }
+            Err(_) => break
+        };
+
+        let line = match monitor
+        {
+            Some(monitor) => bars.iter()
+                .find(|bar| bar.monitor == monitor)
+                .map(|bar| tabs::format_tab_bar(bar, &format))
+                .unwrap_or(String::new()),
+            None => bars.iter()
+                .map(|bar| tabs::format_tab_bar(bar, &format))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        let mut stdout = stdout.lock();
+        if writeln!(stdout, "{}", line).and_then(|_| stdout.flush()).is_err()
+        {
+            break
+        }
+    }
+}
 
 pub fn main()
 {
@@ -103,16 +377,54 @@ pub fn main()
         .about("controls stacks")
         .arg(Arg::with_name("command")
             .required(true)
-            .help("Primary command. {create, move}"))
+            .help("Primary command. {create, remove, move, add, eject, mode, list, focus_current, update}"))
         .arg(Arg::with_name("parameters")
-            .help("Additional parameters to the comand"));
+            .multiple(true)
+            .help("Additional parameters to the comand"))
+        .arg(Arg::with_name("json")
+            .long("json")
+            .help("Print the output of list as json"))
+        .arg(Arg::with_name("layout")
+            .long("layout")
+            .takes_value(true)
+            .help("The mode of a created stack. {collapsed, hidden, neighbours, equal}"));
+
+    let tabs_subcommand = SubCommand::with_name("tabs")
+        .about("prints the stack tabs whenever they change, for use in a status bar")
+        .arg(Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .default_value("plain")
+            .help("Output format. {lemonbar, polybar, plain, json}"))
+        .arg(Arg::with_name("monitor")
+            .long("monitor")
+            .takes_value(true)
+            .help("Only print the tabs on this monitor"));
 
     let arg_parser = App::new("stack_client")
         .about("Client for bspwm stackable windows")
-        .subcommand(stack_subcommand);
+        .arg(Arg::with_name("tcp")
+            .long("tcp")
+            .help("Connect to rspwm over TCP instead of a UNIX socket"))
+        .subcommand(stack_subcommand)
+        .subcommand(tabs_subcommand);
 
     let matches = arg_parser.get_matches();
 
+    let config = Config::load_or_default();
+
+    let server = match Transport::select(matches.is_present("tcp"), config.tcp_port)
+    {
+        Ok(transport) => Server {
+            transport: transport,
+            timeout: Duration::new(config.timeout_seconds, 0)
+        },
+        Err(msg) => {
+            println!("{}", msg);
+            return
+        }
+    };
+
     if let Some(matches) = matches.subcommand_matches("stack")
     {
         if matches.is_present("command")
@@ -123,18 +435,41 @@ pub fn main()
             match command
             {
                 "create" => {
-                    do_create_stack();
+                    do_create_stack(&server, matches.value_of("layout"));
                 },
                 "focus_current" => {
-                    do_focus_current();
+                    do_focus_current(&server);
                 },
                 "remove" => 
                 {
-                    do_remove_focused_stack()
+                    do_remove_focused_stack(&server)
                 },
                 "update" =>
                 {
-                    do_update_stacks()
+                    do_update_stacks(&server)
+                }
+                "move" =>
+                {
+                    do_move(&server, matches.value_of("parameters"))
+                }
+                "add" =>
+                {
+                    let parameters = matches.values_of("parameters")
+                        .map(|values| values.collect())
+                        .unwrap_or(vec!());
+                    do_add_to_stack(&server, parameters)
+                }
+                "eject" =>
+                {
+                    do_eject(&server)
+                }
+                "mode" =>
+                {
+                    do_set_stack_mode(&server, matches.value_of("parameters"))
+                }
+                "list" =>
+                {
+                    do_list_stacks(&server, matches.is_present("json"))
                 }
                 other => {
                     println!("unexpected stack command: {}", other);
@@ -146,8 +481,47 @@ pub fn main()
             println!("Invalid subcommand");
         }
     }
+    else if let Some(matches) = matches.subcommand_matches("tabs")
+    {
+        do_follow_tabs(matches.value_of("format").unwrap(), matches.value_of("monitor"))
+    }
     else
     {
         println!("No subcommand specified");
     }
 }
+
+
+
+#[cfg(test)]
+mod client_tests
+{
+    use super::*;
+
+    use bspwm::SplitDirection;
+
+    #[test]
+    fn format_stack_info_test()
+    {
+        let info = StackInfo {
+            root: 4194636,
+            desktop: Some(1),
+            desktop_name: Some("I".to_string()),
+            monitor: Some(2),
+            monitor_name: None,
+            split: SplitDirection::Horizontal,
+            leaves: vec!(29526298, 29541313, 29541339),
+            members: vec!(vec!(29526298), vec!(29541313, 29541339)),
+            expanded: Some(29541313)
+        };
+
+        assert_eq!(
+                format_stack_info(&info),
+                concat!(
+                    "0x0040014C on desktop I (0x00000001), monitor 0x00000002, Horizontal\n",
+                    "    0x01C2891A\n",
+                    "  * 0x01C2C3C1 0x01C2C3DB\n"
+                )
+            );
+    }
+}
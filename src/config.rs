@@ -0,0 +1,751 @@
+extern crate toml;
+
+use transport::TCP_PORT;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/**
+  How a stack makes room for its expanded window. Each mode has a layout
+  in `layout`
+*/
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum StackMode
+{
+    /// The other windows are shrunk to `collapsed_window_size`
+    Collapsed,
+    /// The other windows are hidden, like a monocle layout
+    Hidden,
+    /// The windows next to the expanded one share half of the stack, the
+    /// others are hidden
+    Neighbours,
+    /// All the windows get the same amount of space
+    Equal
+}
+
+/**
+  The names that `StackMode::from_str` accepts
+*/
+pub const STACK_MODES: &'static str = "collapsed, hidden, neighbours, equal";
+
+impl StackMode
+{
+    pub fn from_str(string: &str) -> Option<StackMode>
+    {
+        match string
+        {
+            "collapsed" => Some(StackMode::Collapsed),
+            "hidden" => Some(StackMode::Hidden),
+            "neighbours" => Some(StackMode::Neighbours),
+            "equal" => Some(StackMode::Equal),
+            _ => None
+        }
+    }
+}
+
+/**
+  How an animation moves from the old ratios to the new ones over time
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum Easing
+{
+    Linear,
+    /// Starts fast and slows down at the end
+    EaseOut,
+    /// Starts slow, speeds up and slows down at the end
+    EaseInOut
+}
+
+impl Easing
+{
+    pub fn from_str(string: &str) -> Option<Easing>
+    {
+        match string
+        {
+            "linear" => Some(Easing::Linear),
+            "ease_out" => Some(Easing::EaseOut),
+            "ease_in_out" => Some(Easing::EaseInOut),
+            _ => None
+        }
+    }
+}
+
+/**
+  Where the notifications for an event are sent
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum NotificationBackend
+{
+    /// Desktop notifications, each one replaces the previous one
+    Desktop,
+    /// A line on stderr
+    Log,
+    None,
+    /// Runs the program with the arguments, followed by the event name and message
+    Command(Vec<String>)
+}
+
+/**
+  The events that the backend of notifications can be chosen for. `default`
+  is used for the events without a backend of their own
+*/
+pub const NOTIFICATION_EVENTS: [&'static str; 5] =
+    ["default", "stack_created", "stack_removed", "already_a_stack", "no_focused_window"];
+
+/**
+  The stack changes that hook commands can be run for
+*/
+pub const HOOK_EVENTS: [&'static str; 5] =
+    ["stack_created", "stack_removed", "member_added", "member_removed", "expanded_changed"];
+
+/**
+  Settings that can be changed for the stacks on a specific desktop or monitor
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackSettings
+{
+    /// The visible size of collapsed windows in pixels
+    pub collapsed_window_size: u64,
+    /// Whether the first leaf of a new stack is expanded
+    pub focus_first_leaf_on_create: bool,
+    /// The mode of new stacks
+    pub mode: StackMode
+}
+
+/**
+  Stack settings for a desktop or monitor. Missing values are taken from the
+  less specific settings
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackOverrides
+{
+    pub collapsed_window_size: Option<u64>,
+    pub focus_first_leaf_on_create: Option<bool>,
+    pub mode: Option<StackMode>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config
+{
+    pub tcp_port: u16,
+    pub timeout_seconds: u64,
+    pub notification_timeout_ms: u64,
+    /// Notification backends by event name
+    pub notifications: BTreeMap<String, NotificationBackend>,
+    /// Commands to run when stacks change, by event name
+    pub hooks: BTreeMap<String, Vec<String>>,
+    /// How long a hook may run before it is killed
+    pub hook_timeout_ms: u64,
+    /// Whether `irspc focus` wraps around to the other side of the screens
+    pub focus_wrap: bool,
+    /// How long stack layout changes are animated for, 0 disables animations
+    pub animation_duration_ms: u64,
+    pub animation_easing: Easing,
+    pub stacks: StackSettings,
+    /// Overrides by desktop name
+    pub desktops: BTreeMap<String, StackOverrides>,
+    /// Overrides by monitor name
+    pub monitors: BTreeMap<String, StackOverrides>
+}
+
+impl Config
+{
+    pub fn default() -> Config
+    {
+        Config {
+            tcp_port: TCP_PORT,
+            timeout_seconds: 1,
+            notification_timeout_ms: 2000,
+            notifications: BTreeMap::new(),
+            hooks: BTreeMap::new(),
+            hook_timeout_ms: 5000,
+            focus_wrap: false,
+            animation_duration_ms: 0,
+            animation_easing: Easing::EaseOut,
+            stacks: StackSettings {
+                //About one line of terminal text
+                collapsed_window_size: 20,
+                focus_first_leaf_on_create: true,
+                mode: StackMode::Collapsed
+            },
+            desktops: BTreeMap::new(),
+            monitors: BTreeMap::new()
+        }
+    }
+
+    /**
+      Parses a config file. Every syntax error, unknown key and invalid value
+      is reported, one per line
+    */
+    pub fn parse(text: &str) -> Result<Config, String>
+    {
+        let mut parser = toml::Parser::new(text);
+
+        let table = match parser.parse()
+        {
+            Some(table) => table,
+            None => {
+                let errors = parser.errors.iter()
+                    .map(|error| {
+                        let (line, column) = parser.to_linecol(error.lo);
+                        format!("{}:{}: {}", line + 1, column + 1, error.desc)
+                    })
+                    .collect::<Vec<_>>();
+
+                return Err(errors.join("\n"))
+            }
+        };
+
+        let mut errors = vec!();
+        let mut config = Config::default();
+        let mut overrides = StackOverrides::none();
+
+        for (key, value) in &table
+        {
+            match key.as_str()
+            {
+                "tcp_port" => {
+                    match integer_value(key, value, 1, u16::max_value() as i64)
+                    {
+                        Ok(port) => config.tcp_port = port as u16,
+                        Err(msg) => errors.push(msg)
+                    }
+                }
+                "timeout_seconds" => {
+                    match integer_value(key, value, 1, i64::max_value())
+                    {
+                        Ok(timeout) => config.timeout_seconds = timeout,
+                        Err(msg) => errors.push(msg)
+                    }
+                }
+                "notification_timeout_ms" => {
+                    match integer_value(key, value, 0, i32::max_value() as i64)
+                    {
+                        Ok(timeout) => config.notification_timeout_ms = timeout,
+                        Err(msg) => errors.push(msg)
+                    }
+                }
+                "focus_wrap" => {
+                    match *value
+                    {
+                        toml::Value::Boolean(wrap) => config.focus_wrap = wrap,
+                        _ => errors.push(format!("'{}' must be a boolean", key))
+                    }
+                }
+                "animation_duration_ms" => {
+                    match integer_value(key, value, 0, i32::max_value() as i64)
+                    {
+                        Ok(duration) => config.animation_duration_ms = duration,
+                        Err(msg) => errors.push(msg)
+                    }
+                }
+                "animation_easing" => {
+                    let easing = match *value
+                    {
+                        toml::Value::String(ref easing) => Easing::from_str(easing),
+                        _ => None
+                    };
+
+                    match easing
+                    {
+                        Some(easing) => config.animation_easing = easing,
+                        None => errors.push(format!("'{}' must be one of linear, ease_out, ease_in_out", key))
+                    }
+                }
+                "notifications" => {
+                    if let Some(notifications) = notification_table(key, value, &mut errors)
+                    {
+                        config.notifications = notifications;
+                    }
+                }
+                "hooks" => {
+                    if let Some(hooks) = hook_table(key, value, &mut errors)
+                    {
+                        config.hooks = hooks;
+                    }
+                }
+                "hook_timeout_ms" => {
+                    match integer_value(key, value, 1, i32::max_value() as i64)
+                    {
+                        Ok(timeout) => config.hook_timeout_ms = timeout,
+                        Err(msg) => errors.push(msg)
+                    }
+                }
+                "desktops" | "monitors" => {
+                    let overrides = match override_tables(key, value, &mut errors)
+                    {
+                        Some(overrides) => overrides,
+                        None => continue
+                    };
+
+                    match key.as_str()
+                    {
+                        "desktops" => config.desktops = overrides,
+                        _ => config.monitors = overrides
+                    }
+                }
+                _ => {
+                    if !read_stack_setting(&mut overrides, key, key, value, &mut errors)
+                    {
+                        errors.push(format!("Unknown key '{}'", key))
+                    }
+                }
+            }
+        }
+
+        config.stacks = overrides.apply(&config.stacks);
+
+        match errors.is_empty()
+        {
+            true => Ok(config),
+            false => Err(errors.join("\n"))
+        }
+    }
+
+    /**
+      Reads the config file at the path. The default config is used if the
+      file does not exist
+    */
+    pub fn load(path: &Path) -> Result<Config, String>
+    {
+        let mut file = match File::open(path)
+        {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(format!("Failed to open {}: {}", path.display(), e))
+        };
+
+        let mut text = String::new();
+        file.read_to_string(&mut text)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+        Config::parse(&text).map_err(|msg| format!("Invalid config file {}:\n{}", path.display(), msg))
+    }
+
+    /**
+      Loads the config file from the default location, falling back to the
+      default config if it is invalid
+    */
+    pub fn load_or_default() -> Config
+    {
+        match config_file_path().map(|path| Config::load(&path))
+        {
+            Some(Ok(config)) => config,
+            Some(Err(msg)) => {
+                println!("{}", msg);
+                Config::default()
+            }
+            None => Config::default()
+        }
+    }
+
+    /**
+      Returns the notification backend for an event, falling back to the
+      default one and then to desktop notifications
+    */
+    pub fn notification_backend(&self, event: &str) -> NotificationBackend
+    {
+        self.notifications.get(event)
+            .or(self.notifications.get("default"))
+            .cloned()
+            .unwrap_or(NotificationBackend::Desktop)
+    }
+
+    /**
+      Returns the settings for stacks on a desktop and monitor. Desktop settings
+      take precedence over monitor settings
+    */
+    pub fn stack_settings(&self, desktop: Option<&str>, monitor: Option<&str>) -> StackSettings
+    {
+        let monitor_settings = match monitor.and_then(|monitor| self.monitors.get(monitor))
+        {
+            Some(overrides) => overrides.apply(&self.stacks),
+            None => self.stacks.clone()
+        };
+
+        match desktop.and_then(|desktop| self.desktops.get(desktop))
+        {
+            Some(overrides) => overrides.apply(&monitor_settings),
+            None => monitor_settings
+        }
+    }
+}
+
+impl StackOverrides
+{
+    fn none() -> StackOverrides
+    {
+        StackOverrides {
+            collapsed_window_size: None,
+            focus_first_leaf_on_create: None,
+            mode: None
+        }
+    }
+
+    fn apply(&self, settings: &StackSettings) -> StackSettings
+    {
+        StackSettings {
+            collapsed_window_size: self.collapsed_window_size.unwrap_or(settings.collapsed_window_size),
+            focus_first_leaf_on_create: self.focus_first_leaf_on_create
+                .unwrap_or(settings.focus_first_leaf_on_create),
+            mode: self.mode.clone().unwrap_or(settings.mode.clone())
+        }
+    }
+}
+
+/**
+  Reads a key if it is a stack setting. Returns false if it is not
+*/
+fn read_stack_setting(
+        overrides: &mut StackOverrides,
+        name: &str,
+        key: &str,
+        value: &toml::Value,
+        errors: &mut Vec<String>
+    ) -> bool
+{
+    match key
+    {
+        "collapsed_window_size" => {
+            match integer_value(name, value, 1, i64::max_value())
+            {
+                Ok(size) => overrides.collapsed_window_size = Some(size),
+                Err(msg) => errors.push(msg)
+            }
+        }
+        "focus_first_leaf_on_create" => {
+            match *value
+            {
+                toml::Value::Boolean(focus) => overrides.focus_first_leaf_on_create = Some(focus),
+                _ => errors.push(format!("'{}' must be a boolean", name))
+            }
+        }
+        "stack_mode" => {
+            let mode = match *value
+            {
+                toml::Value::String(ref mode) => StackMode::from_str(mode),
+                _ => None
+            };
+
+            match mode
+            {
+                Some(mode) => overrides.mode = Some(mode),
+                None => errors.push(format!("'{}' must be one of {}", name, STACK_MODES))
+            }
+        }
+        _ => return false
+    }
+
+    true
+}
+
+/**
+  Reads the `[desktops.<name>]` or `[monitors.<name>]` tables
+*/
+fn override_tables(key: &str, value: &toml::Value, errors: &mut Vec<String>)
+    -> Option<BTreeMap<String, StackOverrides>>
+{
+    let table = match *value
+    {
+        toml::Value::Table(ref table) => table,
+        _ => {
+            errors.push(format!("'{}' must be a table", key));
+            return None
+        }
+    };
+
+    let mut result = BTreeMap::new();
+    for (name, value) in table
+    {
+        match *value
+        {
+            toml::Value::Table(ref settings) => {
+                let mut overrides = StackOverrides::none();
+
+                for (setting, value) in settings
+                {
+                    let full_name = format!("{}.{}.{}", key, name, setting);
+
+                    if !read_stack_setting(&mut overrides, &full_name, setting, value, errors)
+                    {
+                        errors.push(format!("Unknown key '{}'", full_name))
+                    }
+                }
+
+                result.insert(name.clone(), overrides);
+            }
+            _ => errors.push(format!("'{}.{}' must be a table", key, name))
+        }
+    }
+
+    Some(result)
+}
+
+/**
+  Reads the `[notifications]` table. Each event is either the name of a
+  backend or an array with a command to run
+*/
+fn notification_table(key: &str, value: &toml::Value, errors: &mut Vec<String>)
+    -> Option<BTreeMap<String, NotificationBackend>>
+{
+    let table = match *value
+    {
+        toml::Value::Table(ref table) => table,
+        _ => {
+            errors.push(format!("'{}' must be a table", key));
+            return None
+        }
+    };
+
+    let mut result = BTreeMap::new();
+    for (event, value) in table
+    {
+        let full_name = format!("{}.{}", key, event);
+
+        if !NOTIFICATION_EVENTS.contains(&event.as_str())
+        {
+            errors.push(format!("Unknown key '{}'", full_name));
+            continue
+        }
+
+        let backend = match *value
+        {
+            toml::Value::String(ref name) => match name.as_str()
+            {
+                "desktop" => Some(NotificationBackend::Desktop),
+                "log" => Some(NotificationBackend::Log),
+                "none" => Some(NotificationBackend::None),
+                _ => None
+            },
+            _ => command_value(value).map(NotificationBackend::Command)
+        };
+
+        match backend
+        {
+            Some(backend) => {
+                result.insert(event.clone(), backend);
+            }
+            None => errors.push(format!(
+                    "'{}' must be one of desktop, log, none or a command as an array of strings",
+                    full_name
+                ))
+        }
+    }
+
+    Some(result)
+}
+
+/**
+  Reads the `[hooks]` table of commands to run for each event
+*/
+fn hook_table(key: &str, value: &toml::Value, errors: &mut Vec<String>)
+    -> Option<BTreeMap<String, Vec<String>>>
+{
+    let table = match *value
+    {
+        toml::Value::Table(ref table) => table,
+        _ => {
+            errors.push(format!("'{}' must be a table", key));
+            return None
+        }
+    };
+
+    let mut result = BTreeMap::new();
+    for (event, value) in table
+    {
+        let full_name = format!("{}.{}", key, event);
+
+        if !HOOK_EVENTS.contains(&event.as_str())
+        {
+            errors.push(format!("Unknown key '{}'", full_name));
+            continue
+        }
+
+        match command_value(value)
+        {
+            Some(command) => {
+                result.insert(event.clone(), command);
+            }
+            None => errors.push(format!("'{}' must be a command as an array of strings", full_name))
+        }
+    }
+
+    Some(result)
+}
+
+/**
+  Reads a command as a non-empty array with the program and its arguments
+*/
+fn command_value(value: &toml::Value) -> Option<Vec<String>>
+{
+    match *value
+    {
+        toml::Value::Array(ref command) if !command.is_empty() => {
+            command.iter()
+                .map(|argument| argument.as_str().map(String::from))
+                .collect()
+        }
+        _ => None
+    }
+}
+
+fn integer_value(key: &str, value: &toml::Value, min: i64, max: i64) -> Result<u64, String>
+{
+    match *value
+    {
+        toml::Value::Integer(number) if number >= min && number <= max => Ok(number as u64),
+        toml::Value::Integer(_) => Err(format!("'{}' must be between {} and {}", key, min, max)),
+        _ => Err(format!("'{}' must be an integer", key))
+    }
+}
+
+/**
+  Returns the path of the config file, `$XDG_CONFIG_HOME/rspwm/config.toml`
+  or `~/.config/rspwm/config.toml`
+*/
+pub fn config_file_path() -> Option<PathBuf>
+{
+    env::var("XDG_CONFIG_HOME").ok()
+        .filter(|directory| !directory.is_empty())
+        .map(PathBuf::from)
+        .or(env::var("HOME").ok().map(|home| PathBuf::from(home).join(".config")))
+        .map(|directory| directory.join("rspwm").join("config.toml"))
+}
+
+
+#[cfg(test)]
+mod config_tests
+{
+    use super::*;
+
+    #[test]
+    fn parse_config_test()
+    {
+        let config = Config::parse(r#"
+            timeout_seconds = 2
+            collapsed_window_size = 30
+            focus_wrap = true
+            animation_duration_ms = 150
+            animation_easing = "linear"
+
+            [monitors.DP-1]
+            collapsed_window_size = 40
+            focus_first_leaf_on_create = false
+
+            [desktops.I]
+            collapsed_window_size = 50
+            stack_mode = "hidden"
+        "#).unwrap();
+
+        assert_eq!(config.timeout_seconds, 2);
+        assert_eq!(config.tcp_port, TCP_PORT);
+        assert!(config.focus_wrap);
+        assert_eq!(config.animation_duration_ms, 150);
+        assert_eq!(config.animation_easing, Easing::Linear);
+
+        let settings = |desktop, monitor| config.stack_settings(desktop, monitor);
+        assert_eq!(settings(None, None), StackSettings {
+            collapsed_window_size: 30,
+            focus_first_leaf_on_create: true,
+            mode: StackMode::Collapsed
+        });
+        assert_eq!(settings(Some("II"), Some("DP-1")), StackSettings {
+            collapsed_window_size: 40,
+            focus_first_leaf_on_create: false,
+            mode: StackMode::Collapsed
+        });
+        assert_eq!(settings(Some("I"), Some("DP-1")), StackSettings {
+            collapsed_window_size: 50,
+            focus_first_leaf_on_create: false,
+            mode: StackMode::Hidden
+        });
+
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn invalid_config_test()
+    {
+        let errors = Config::parse(r#"
+            tcp_port = 100000
+            timeout = 2
+            focus_first_leaf_on_create = "yes"
+
+            [desktops.I]
+            collapsed_window_sise = 10
+            stack_mode = "monocle"
+        "#).unwrap_err();
+
+        assert_eq!(errors.lines().collect::<Vec<_>>(), vec!(
+                "Unknown key 'desktops.I.collapsed_window_sise'",
+                "'desktops.I.stack_mode' must be one of collapsed, hidden, neighbours, equal",
+                "'focus_first_leaf_on_create' must be a boolean",
+                "'tcp_port' must be between 1 and 65535",
+                "Unknown key 'timeout'",
+            ));
+
+        assert!(Config::parse("timeout_seconds = ").unwrap_err().starts_with("1:"));
+    }
+
+    #[test]
+    fn notification_config_test()
+    {
+        let config = Config::parse(r#"
+            [notifications]
+            default = "log"
+            stack_created = "none"
+            no_focused_window = ["notify-send", "-u", "low"]
+        "#).unwrap();
+
+        assert_eq!(config.notification_backend("stack_created"), NotificationBackend::None);
+        assert_eq!(config.notification_backend("stack_removed"), NotificationBackend::Log);
+        assert_eq!(
+                config.notification_backend("no_focused_window"),
+                NotificationBackend::Command(vec!("notify-send".to_string(), "-u".to_string(), "low".to_string()))
+            );
+        assert_eq!(Config::default().notification_backend("stack_created"), NotificationBackend::Desktop);
+
+        let errors = Config::parse(r#"
+            [notifications]
+            stack_created = "popup"
+            stack_removed = []
+            stack_moved = "log"
+        "#).unwrap_err();
+
+        assert_eq!(errors.lines().collect::<Vec<_>>(), vec!(
+                "'notifications.stack_created' must be one of desktop, log, none or a command as an array of strings",
+                "Unknown key 'notifications.stack_moved'",
+                "'notifications.stack_removed' must be one of desktop, log, none or a command as an array of strings",
+            ));
+    }
+
+    #[test]
+    fn hook_config_test()
+    {
+        let config = Config::parse(r#"
+            hook_timeout_ms = 500
+
+            [hooks]
+            stack_created = ["stack-borders", "--created"]
+        "#).unwrap();
+
+        assert_eq!(config.hook_timeout_ms, 500);
+        assert_eq!(config.hooks.get("stack_created"), Some(&vec!("stack-borders".to_string(), "--created".to_string())));
+        assert_eq!(config.hooks.get("stack_removed"), None);
+
+        let errors = Config::parse(r#"
+            [hooks]
+            member_added = "stack-borders"
+            stack_moved = ["stack-borders"]
+        "#).unwrap_err();
+
+        assert_eq!(errors.lines().collect::<Vec<_>>(), vec!(
+                "'hooks.member_added' must be a command as an array of strings",
+                "Unknown key 'hooks.stack_moved'",
+            ));
+    }
+}
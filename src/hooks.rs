@@ -0,0 +1,282 @@
+
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::Config;
+use messages::StackInfo;
+
+/**
+  How often a running hook is checked for having exited
+*/
+const POLL_MS: u64 = 10;
+
+/**
+  A change to a stack that hooks can be run for
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookEvent
+{
+    StackCreated,
+    StackRemoved,
+    MemberAdded(u64),
+    MemberRemoved(u64),
+    ExpandedChanged
+}
+
+impl HookEvent
+{
+    /**
+      The name of the event in the `[hooks]` config table
+    */
+    pub fn name(&self) -> &'static str
+    {
+        match *self
+        {
+            HookEvent::StackCreated => "stack_created",
+            HookEvent::StackRemoved => "stack_removed",
+            HookEvent::MemberAdded(_) => "member_added",
+            HookEvent::MemberRemoved(_) => "member_removed",
+            HookEvent::ExpandedChanged => "expanded_changed"
+        }
+    }
+}
+
+/**
+  An event together with the stack it happened to. Removed stacks are
+  described as they were before the removal
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hook
+{
+    pub event: HookEvent,
+    pub stack: StackInfo
+}
+
+/**
+  Compares the stacks before and after a command and returns what changed.
+  New stacks are only reported as created, and removed stacks only as removed
+*/
+pub fn stack_changes(previous: &Vec<StackInfo>, current: &Vec<StackInfo>) -> Vec<Hook>
+{
+    let mut result = vec!();
+
+    for old in previous
+    {
+        if !current.iter().any(|stack| stack.root == old.root)
+        {
+            result.push(Hook{event: HookEvent::StackRemoved, stack: old.clone()});
+        }
+    }
+
+    for stack in current
+    {
+        let old = match previous.iter().find(|old| old.root == stack.root)
+        {
+            Some(old) => old,
+            None => {
+                result.push(Hook{event: HookEvent::StackCreated, stack: stack.clone()});
+                continue
+            }
+        };
+
+        for leaf in old.leaves.iter().filter(|leaf| !stack.leaves.contains(leaf))
+        {
+            result.push(Hook{event: HookEvent::MemberRemoved(*leaf), stack: stack.clone()});
+        }
+        for leaf in stack.leaves.iter().filter(|leaf| !old.leaves.contains(leaf))
+        {
+            result.push(Hook{event: HookEvent::MemberAdded(*leaf), stack: stack.clone()});
+        }
+
+        if old.expanded != stack.expanded
+        {
+            result.push(Hook{event: HookEvent::ExpandedChanged, stack: stack.clone()});
+        }
+    }
+
+    result
+}
+
+fn format_node(node: Option<u64>) -> String
+{
+    node.map(|id| format!("0x{:08X}", id)).unwrap_or(String::new())
+}
+
+/**
+  The environment variables that a hook is run with. Nodes that don't exist
+  are empty strings
+*/
+pub fn environment(hook: &Hook, focused: Option<u64>) -> Vec<(String, String)>
+{
+    let members = hook.stack.leaves.iter()
+        .map(|leaf| format_node(Some(*leaf)))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let node = match hook.event
+    {
+        HookEvent::MemberAdded(node) | HookEvent::MemberRemoved(node) => Some(node),
+        _ => None
+    };
+
+    vec!(
+        ("RSPWM_EVENT", hook.event.name().to_string()),
+        ("RSPWM_STACK_ROOT", format_node(Some(hook.stack.root))),
+        ("RSPWM_DESKTOP", hook.stack.desktop_name.clone().unwrap_or(String::new())),
+        ("RSPWM_DESKTOP_ID", format_node(hook.stack.desktop)),
+        ("RSPWM_MEMBERS", members),
+        ("RSPWM_EXPANDED", format_node(hook.stack.expanded)),
+        ("RSPWM_FOCUSED", format_node(focused)),
+        ("RSPWM_NODE", format_node(node)),
+    ).into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+}
+
+/**
+  Runs a hook command and waits for it to exit. Hooks that are still running
+  after the timeout are killed
+*/
+fn run_with_timeout(command: &Vec<String>, environment: &Vec<(String, String)>, timeout: Duration)
+    -> Result<(), String>
+{
+    let mut child = process::Command::new(&command[0])
+        .args(&command[1..])
+        .envs(environment.iter().cloned())
+        .spawn()
+        .map_err(|e| format!("Failed to run hook {}: {}", command[0], e))?;
+
+    let start = Instant::now();
+    loop
+    {
+        match child.try_wait()
+        {
+            Ok(Some(status)) if status.success() => return Ok(()),
+            Ok(Some(status)) => return Err(format!("Hook {} failed with {}", command[0], status)),
+            Ok(None) => {}
+            Err(e) => return Err(format!("Failed to wait for hook {}: {}", command[0], e))
+        }
+
+        if start.elapsed() >= timeout
+        {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("Hook {} was killed after {} ms", command[0], timeout.as_millis()))
+        }
+
+        thread::sleep(Duration::from_millis(POLL_MS));
+    }
+}
+
+/**
+  Runs the configured hooks for the changes on a separate thread so that slow
+  hooks don't hold up the daemon. The hooks for one command run in order
+*/
+pub fn run(config: &Config, hooks: Vec<Hook>, focused: Option<u64>)
+{
+    let commands = hooks.iter()
+        .filter_map(|hook| {
+            config.hooks.get(hook.event.name())
+                .map(|command| (command.clone(), environment(hook, focused)))
+        })
+        .collect::<Vec<_>>();
+
+    if commands.is_empty()
+    {
+        return
+    }
+
+    let timeout = Duration::from_millis(config.hook_timeout_ms);
+    thread::spawn(move || {
+        for (command, environment) in commands
+        {
+            if let Err(msg) = run_with_timeout(&command, &environment, timeout)
+            {
+                println!("{}", msg);
+            }
+        }
+    });
+}
+
+
+#[cfg(test)]
+mod hooks_tests
+{
+    use super::*;
+    use bspwm::SplitDirection;
+
+    fn info(root: u64, leaves: Vec<u64>, expanded: Option<u64>) -> StackInfo
+    {
+        StackInfo {
+            root: root,
+            desktop: Some(1),
+            desktop_name: Some("I".to_string()),
+            monitor: Some(2),
+            monitor_name: Some("DP-1".to_string()),
+            split: SplitDirection::Horizontal,
+            leaves: leaves.clone(),
+            members: leaves.into_iter().map(|leaf| vec!(leaf)).collect(),
+            expanded: expanded
+        }
+    }
+
+    #[test]
+    fn stack_changes_test()
+    {
+        let previous = vec!(info(1, vec!(10, 11), Some(10)), info(2, vec!(20), None));
+        let current = vec!(info(1, vec!(11, 12), Some(11)), info(3, vec!(30), Some(30)));
+
+        let events = stack_changes(&previous, &current).into_iter()
+            .map(|hook| (hook.event, hook.stack.root))
+            .collect::<Vec<_>>();
+
+        assert_eq!(events, vec!(
+                (HookEvent::StackRemoved, 2),
+                (HookEvent::MemberRemoved(10), 1),
+                (HookEvent::MemberAdded(12), 1),
+                (HookEvent::ExpandedChanged, 1),
+                (HookEvent::StackCreated, 3),
+            ));
+
+        assert_eq!(stack_changes(&current, &current), vec!());
+    }
+
+    #[test]
+    fn environment_test()
+    {
+        let hook = Hook{event: HookEvent::MemberAdded(0x1C2C3F3), stack: info(0x400144, vec!(0x1C2C3F3, 0x1C00003), None)};
+
+        let environment = environment(&hook, Some(0x1C00003));
+        let variable = |name: &str| environment.iter()
+            .find(|&&(ref variable, _)| variable == name)
+            .map(|&(_, ref value)| value.as_str());
+
+        assert_eq!(variable("RSPWM_EVENT"), Some("member_added"));
+        assert_eq!(variable("RSPWM_STACK_ROOT"), Some("0x00400144"));
+        assert_eq!(variable("RSPWM_DESKTOP"), Some("I"));
+        assert_eq!(variable("RSPWM_MEMBERS"), Some("0x01C2C3F3 0x01C00003"));
+        assert_eq!(variable("RSPWM_EXPANDED"), Some(""));
+        assert_eq!(variable("RSPWM_FOCUSED"), Some("0x01C00003"));
+        assert_eq!(variable("RSPWM_NODE"), Some("0x01C2C3F3"));
+    }
+
+    #[test]
+    fn run_with_timeout_test()
+    {
+        let environment = vec!(("RSPWM_EVENT".to_string(), "stack_created".to_string()));
+        let sh = |script: &str| vec!("sh".to_string(), "-c".to_string(), script.to_string());
+        let timeout = Duration::from_millis(100);
+
+        assert_eq!(run_with_timeout(&sh("test \"$RSPWM_EVENT\" = stack_created"), &environment, timeout), Ok(()));
+        assert!(run_with_timeout(&sh("exit 1"), &environment, timeout).is_err());
+        assert!(run_with_timeout(&vec!("yoloswagmannen".to_string()), &environment, timeout).is_err());
+
+        let start = Instant::now();
+        assert_eq!(
+                run_with_timeout(&sh("sleep 5"), &environment, timeout),
+                Err("Hook sh was killed after 100 ms".to_string())
+            );
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
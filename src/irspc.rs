@@ -9,11 +9,21 @@ extern crate typed_messages;
 mod bspwm;
 mod subprogram;
 mod messages;
+mod backend;
+mod transport;
+mod config;
 
-use bspwm::{FocusTarget, CardinalDirection};
+use bspwm::{FocusTarget, CardinalDirection, BspwmError};
+use backend::Backend;
+use config::Config;
+use messages::{Command, CommandResponse, StackInfo};
+use transport::Transport;
 
 use clap::{App, Arg, SubCommand};
 
+use std::process;
+use std::time::Duration;
+
 pub fn focus_neighbour(
         neighbour: Option<u64>,
         current_desktop_nodes: Vec<u64>,
@@ -33,7 +43,12 @@ pub fn focus_neighbour(
             }
             else
             {
-                FocusTarget::Desktop(neighbour_desktop.unwrap())
+                //There is no desktop in that direction at the edge of the last monitor
+                match neighbour_desktop
+                {
+                    Some(neighbour_desktop) => FocusTarget::Desktop(neighbour_desktop),
+                    None => FocusTarget::None
+                }
             }
         }
         None => {
@@ -46,45 +61,356 @@ pub fn focus_neighbour(
     }
 }
 
-pub fn focus_direction(direction: CardinalDirection)
+/**
+    Takes steps from `start` for as long as `step` finds something and returns
+    where it ended up. None if the first step finds nothing
+*/
+pub fn furthest<F>(start: u64, mut step: F) -> Result<Option<u64>, BspwmError>
+    where F: FnMut(u64) -> Result<Option<u64>, BspwmError>
+{
+    let mut visited = vec!(start);
+
+    loop
+    {
+        //Some layouts have neighbours that lead back to where we started
+        match step(*visited.last().unwrap())?
+        {
+            Some(next) if !visited.contains(&next) => visited.push(next),
+            _ => break
+        }
+    }
+
+    match visited.len()
+    {
+        1 => Ok(None),
+        _ => Ok(visited.pop())
+    }
+}
+
+/**
+    Finds what should be focused when moving the focus in a direction from
+    `source`, or from the focused desktop if there is no source node.
+
+    If there is no window in that direction on the current desktop, the focus
+    moves to the desktop on the next monitor. With `wrap`, moving past the
+    last monitor goes to the monitor at the other end and with a single
+    monitor it goes to the window at the other end of the desktop
+*/
+pub fn focus_direction(bspc: &dyn Backend, source: Option<u64>, direction: CardinalDirection, wrap: bool)
+    -> Result<FocusTarget, BspwmError>
 {
-    let current_node = bspwm::get_focused_node();
+    let current_node = source;
+    let current_desktop = bspwm::get_focused_desktop(bspc)?;
+    let current_monitor = bspwm::get_focused_monitor(bspc)?;
 
     let neighbour = match current_node
     {
-        Some(node) => bspwm::get_neighbouring_node(node, direction),
+        Some(node) => bspwm::get_neighbouring_node(bspc, node, direction.clone())?,
+        None => None
+    };
+
+    let neighbour_monitor = match bspwm::get_neighbouring_monitor(bspc, current_monitor, &direction)?
+    {
+        Some(monitor) => Some(monitor),
+        None if wrap => furthest(current_monitor, |monitor| {
+            bspwm::get_neighbouring_monitor(bspc, monitor, &direction.opposite())
+        })?,
+        None => None
+    };
+
+    let neighbour_desktop = match neighbour_monitor
+    {
+        Some(monitor) => Some(bspwm::get_monitor_desktop(bspc, monitor)?),
         None => None
     };
 
+    let current_desktop_nodes = bspwm::get_nodes_on_desktop(bspc, current_desktop)?;
+    let neighbour_desktop_nodes = match neighbour_desktop
+    {
+        Some(desktop) => bspwm::get_nodes_on_desktop(bspc, desktop)?,
+        None => vec!()
+    };
+
+    let target = focus_neighbour(
+            neighbour,
+            current_desktop_nodes.clone(),
+            neighbour_desktop_nodes,
+            neighbour_desktop
+        );
+
+    match (target, current_node)
+    {
+        (FocusTarget::None, Some(node)) if wrap => {
+            //bspwm also looks for neighbours on the other monitors, those
+            //don't count when wrapping on the desktop
+            let wrapped = furthest(node, |node| {
+                bspwm::get_neighbouring_node(bspc, node, direction.opposite())
+                    .map(|neighbour| neighbour.filter(|neighbour| current_desktop_nodes.contains(neighbour)))
+            })?;
+
+            Ok(wrapped.map(FocusTarget::Node).unwrap_or(FocusTarget::None))
+        }
+        (target, _) => Ok(target)
+    }
+}
+
+/**
+    Redirects the focus to the expanded leaf of a stack when the target is
+    in a stack that the focus is entering. `source` is the node that had the
+    focus and `target_stack` is the innermost stack containing the target
+*/
+pub fn enter_stack(target: FocusTarget, target_stack: Option<&StackInfo>, source: Option<u64>) -> FocusTarget
+{
+    let stack = match (&target, target_stack)
+    {
+        (&FocusTarget::Node(_), Some(stack)) => stack,
+        _ => return target
+    };
+
+    //Moving between the members of a stack that contains the source
+    if source.map(|source| stack.leaves.contains(&source)).unwrap_or(false)
+    {
+        return target
+    }
+
+    match stack.expanded.filter(|expanded| stack.leaves.contains(expanded))
+    {
+        Some(expanded) => FocusTarget::Node(expanded),
+        None => stack.leaves.first().cloned().map(FocusTarget::Node).unwrap_or(target)
+    }
+}
+
+/**
+    Connection to rspwm. The focus falls back to plain bspwm behaviour if
+    rspwm can't be reached
+*/
+struct StackServer
+{
+    transport: Option<Transport>,
+    timeout: Duration
+}
+
+impl StackServer
+{
+    fn send(&self, command: Command) -> Option<CommandResponse>
+    {
+        self.transport.as_ref()
+            .and_then(|transport| transport.send::<_, CommandResponse>(command, Some(self.timeout)).ok())
+    }
+
+    fn is_focused_in_stack(&self) -> bool
+    {
+        match self.send(Command::IsFocusedInStack)
+        {
+            Some(CommandResponse::Yes) => true,
+            _ => false
+        }
+    }
+
+    fn stack_containing(&self, node: u64) -> Option<StackInfo>
+    {
+        match self.send(Command::StackContaining(node))
+        {
+            Some(CommandResponse::Stack(info)) => Some(info),
+            _ => None
+        }
+    }
+}
+
+/**
+    Lets rspwm take a step within the stack containing the focused node.
+    Returns None if rspwm took the step, otherwise the node to continue
+    from, which is the stack root if the step leaves the stack
+*/
+fn step_in_stack(server: &StackServer, focused: u64, command: Command) -> Option<u64>
+{
+    if !server.is_focused_in_stack()
+    {
+        return Some(focused)
+    }
+
+    match server.send(command)
+    {
+        Some(CommandResponse::Done) => None,
+        Some(CommandResponse::EndOfStack) | Some(CommandResponse::UseBspwmFocus) => {
+            server.stack_containing(focused).map(|stack| stack.root).or(Some(focused))
+        }
+        _ => Some(focused)
+    }
+}
+
+/**
+    Returns the target of a move from `source` like `focus_direction`. Targets
+    in a stack that the move enters are replaced by its expanded leaf
+*/
+fn stack_aware_target(
+        bspc: &dyn Backend,
+        server: &StackServer,
+        focused: Option<u64>,
+        source: Option<u64>,
+        direction: CardinalDirection,
+        wrap: bool
+    ) -> Result<FocusTarget, BspwmError>
+{
+    let target = focus_direction(bspc, source, direction, wrap)?;
+
+    let target_stack = match target
+    {
+        FocusTarget::Node(node) => server.stack_containing(node),
+        _ => None
+    };
+
+    Ok(enter_stack(target, target_stack.as_ref(), focused))
+}
+
+/**
+    Moves the focus in a direction like `focus_direction`, but treats the
+    stacks that rspwm manages as a unit.
+
+    Moving along a stack steps through its members and moving out of the
+    first or last member leaves the stack from its root, so it takes a single
+    keypress. Moving into a stack focuses its expanded leaf rather than the
+    collapsed window that happens to be closest.
+
+    Returns false if there was nothing to focus
+*/
+fn focus_with_stacks(bspc: &dyn Backend, server: &StackServer, direction: CardinalDirection, wrap: bool)
+    -> Result<bool, BspwmError>
+{
+    let focused = bspwm::get_focused_node(bspc)?;
+
+    let source = match focused
+    {
+        Some(focused) => {
+            match step_in_stack(server, focused, Command::Move(direction.clone()))
+            {
+                Some(source) => Some(source),
+                //rspwm has already focused the next member
+                None => return Ok(true)
+            }
+        }
+        None => None
+    };
+
+    let target = stack_aware_target(bspc, server, focused, source, direction, wrap)?;
+    bspwm::focus_target(bspc, &target)?;
+
+    Ok(target != FocusTarget::None)
+}
+
+/**
+    Moves the focused window in a direction, or swaps it with the window in
+    that direction. Within a stack the members are reordered, and rspwm lays
+    out the stacks that the window leaves and enters again.
+
+    Returns false if there was nowhere to move the window
+*/
+fn move_with_stacks(
+        bspc: &dyn Backend,
+        server: &StackServer,
+        direction: CardinalDirection,
+        wrap: bool,
+        swap: bool
+    ) -> Result<bool, BspwmError>
+{
+    let focused = match bspwm::get_focused_node(bspc)?
+    {
+        Some(focused) => focused,
+        None => return Ok(false)
+    };
+
+    let source = match step_in_stack(server, focused, Command::ReorderInStack(direction.clone()))
+    {
+        Some(source) => source,
+        None => return Ok(true)
+    };
+
+    let target = stack_aware_target(bspc, server, Some(focused), Some(source), direction, wrap)?;
+    if target == FocusTarget::None
+    {
+        return Ok(false)
+    }
+
+    let command = match swap
+    {
+        true => Command::SwapFocused(target.clone()),
+        false => Command::MoveFocused(target.clone())
+    };
+
+    match server.send(command)
+    {
+        Some(CommandResponse::Done) => Ok(true),
+        Some(CommandResponse::Failed(e)) => Err(e),
+        //Without rspwm there are no stacks to take care of
+        _ => bspwm::move_to_target(bspc, focused, &target, swap).map(|_| true)
+    }
 }
 
 pub fn main()
 {
-    let stack_subcommand = SubCommand::with_name("focus")
-        .about("Change focus to some different window")
-        .arg(Arg::with_name("direction")
-            .required(true)
-            .help("What direction to change focus {north, south, east, west}"));
+    let direction_arg = Arg::with_name("direction")
+        .required(true)
+        .help("The direction {north, south, east, west}");
 
     let arg_parser = App::new("stack_client")
         .about("Wrapper around bspwm for increased control of window movement and focus")
-        .subcommand(stack_subcommand);
+        .arg(Arg::with_name("tcp")
+            .long("tcp")
+            .help("Connect to rspwm over TCP instead of a UNIX socket"))
+        .subcommand(SubCommand::with_name("focus")
+            .about("Change focus to some different window")
+            .arg(direction_arg.clone()))
+        .subcommand(SubCommand::with_name("move")
+            .about("Move the focused window next to the window in a direction")
+            .arg(direction_arg.clone()))
+        .subcommand(SubCommand::with_name("swap")
+            .about("Swap the focused window with the window in a direction")
+            .arg(direction_arg));
 
     let matches = arg_parser.get_matches();
 
-    if let Some(matches) = matches.subcommand_matches("focus")
+    let (subcommand, subcommand_matches) = match matches.subcommand()
     {
-        //do_create_stack();
-        let direction = matches.value_of("direction").unwrap();
+        (name, Some(subcommand_matches)) => (name, subcommand_matches),
+        (_, None) => {
+            println!("No subcommand specified");
+            return
+        }
+    };
 
-        let direction = CardinalDirection::from_str(direction).unwrap();
-        println!("{:?}", direction);
+    let direction = subcommand_matches.value_of("direction").unwrap();
+    let direction = match CardinalDirection::from_str(&direction.to_lowercase())
+    {
+        Some(direction) => direction,
+        None => {
+            println!("Expected a direction {{north, south, east, west}}");
+            process::exit(1)
+        }
+    };
 
-        println!("{:?}", bspwm::get_focused_node());
-    }
-    else
+    let config = Config::load_or_default();
+    let bspc = backend::default_backend();
+
+    let server = StackServer {
+        transport: Transport::select(matches.is_present("tcp"), config.tcp_port).ok(),
+        timeout: Duration::new(config.timeout_seconds, 0)
+    };
+
+    let result = match subcommand
+    {
+        "focus" => focus_with_stacks(&*bspc, &server, direction, config.focus_wrap),
+        "move" => move_with_stacks(&*bspc, &server, direction, config.focus_wrap, false),
+        _ => move_with_stacks(&*bspc, &server, direction, config.focus_wrap, true)
+    };
+
+    match result
     {
-        println!("No subcommand specified");
+        Ok(true) => {},
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1)
+        }
     }
 }
 
@@ -130,6 +456,13 @@ mod focus_tests
         assert_eq!(target, FocusTarget::Desktop(desktop_id));
     }
 
+    #[test]
+    fn node_in_faraway_desktop_at_edge()
+    {
+        let target = focus_neighbour(Some(2), vec!(1259, 19251), vec!(), None);
+        assert_eq!(target, FocusTarget::None);
+    }
+
     #[test]
     fn no_neighbour()
     {
@@ -137,4 +470,55 @@ mod focus_tests
 
         assert_eq!(target, FocusTarget::None);
     }
+
+    fn stack_info(leaves: Vec<u64>, expanded: Option<u64>) -> StackInfo
+    {
+        StackInfo {
+            root: 1,
+            desktop: None,
+            desktop_name: None,
+            monitor: None,
+            monitor_name: None,
+            split: bspwm::SplitDirection::Horizontal,
+            leaves: leaves.clone(),
+            members: leaves.into_iter().map(|leaf| vec!(leaf)).collect(),
+            expanded: expanded
+        }
+    }
+
+    #[test]
+    fn entering_stack()
+    {
+        let stack = stack_info(vec!(10, 11, 12), Some(12));
+
+        //Entering a stack focuses the expanded leaf rather than the closest one
+        let target = enter_stack(FocusTarget::Node(10), Some(&stack), Some(5));
+        assert_eq!(target, FocusTarget::Node(12));
+
+        //or the first leaf if nothing has been expanded
+        let target = enter_stack(FocusTarget::Node(11), Some(&stack_info(vec!(10, 11), None)), Some(5));
+        assert_eq!(target, FocusTarget::Node(10));
+
+        //Moving within the stack is left alone
+        let target = enter_stack(FocusTarget::Node(10), Some(&stack), Some(11));
+        assert_eq!(target, FocusTarget::Node(10));
+
+        let target = enter_stack(FocusTarget::Node(10), None, Some(5));
+        assert_eq!(target, FocusTarget::Node(10));
+
+        let target = enter_stack(FocusTarget::Desktop(3), Some(&stack), Some(5));
+        assert_eq!(target, FocusTarget::Desktop(3));
+    }
+
+    #[test]
+    fn wrap_around()
+    {
+        //Monitors 1, 2, 3 from west to east
+        let west_of = |monitor| Ok(match monitor { 3 => Some(2), 2 => Some(1), _ => None });
+        assert_eq!(furthest(3, west_of), Ok(Some(1)));
+        assert_eq!(furthest(1, west_of), Ok(None));
+
+        //Neighbours that lead back in a circle stop the search
+        assert_eq!(furthest(1, |monitor| Ok(Some(monitor % 2 + 1))), Ok(Some(2)));
+    }
 }
@@ -0,0 +1,578 @@
+
+use animation::{self, Animations};
+use backend::Backend;
+use bspwm::{self, Node, Rectangle, SplitDirection, Children, CollapsedSize, BspwmError};
+use config::{StackMode, Easing};
+
+////////////////////////////////////////////////////////////////////////////////
+//                          Stack layout policies
+////////////////////////////////////////////////////////////////////////////////
+
+/**
+  The part of the bspwm tree that a stack lays out
+*/
+pub struct StackTree<'a>
+{
+    pub root: &'a Node,
+    /// The split direction of the stack root
+    pub direction: SplitDirection,
+    /// Roots of the stacks nested in this one. They are single members
+    pub nested_roots: &'a Vec<u64>
+}
+
+impl<'a> StackTree<'a>
+{
+    pub fn new(root: &'a Node, nested_roots: &'a Vec<u64>) -> StackTree<'a>
+    {
+        StackTree {
+            root: root,
+            direction: bspwm::get_node_split_direction(root),
+            nested_roots: nested_roots
+        }
+    }
+
+    /**
+      The members of the stack in order
+    */
+    pub fn members(&self) -> Vec<&'a Node>
+    {
+        bspwm::find_stack_members(self.root, &self.direction, self.nested_roots)
+    }
+
+    /**
+      Returns the index of the member that contains the node
+    */
+    fn member_containing(&self, id: u64) -> Option<usize>
+    {
+        self.members().iter().position(|member| bspwm::is_node_descendant(member, id))
+    }
+
+    fn is_member(&self, node: &Node) -> bool
+    {
+        bspwm::is_stack_member(node, &self.direction, self.nested_roots)
+    }
+}
+
+/**
+  The split ratios and hidden flags that a layout wants for a stack
+*/
+#[derive(Debug, PartialEq)]
+pub struct Arrangement
+{
+    /// New split ratios by node id, applied in order
+    pub ratios: Vec<(u64, f32)>,
+    /// Whether each member of the stack should be hidden
+    pub hidden: Vec<(u64, bool)>
+}
+
+/**
+  A policy for how a stack shares its space between its members
+*/
+pub trait StackLayout
+{
+    /**
+      Works out the arrangement of the stack when the member containing
+      `focused` is the expanded one
+    */
+    fn arrange(&self, stack: &StackTree, focused: u64) -> Arrangement;
+}
+
+/**
+  Expands the focused member and collapses the others to a few pixels
+*/
+pub struct Accordion
+{
+    pub size: CollapsedSize
+}
+
+/**
+  Shows the focused member and hides the others
+*/
+pub struct Monocle;
+
+/**
+  Expands the focused member to half of the stack and shares the rest
+  between the members next to it. The other members are hidden
+*/
+pub struct Neighbours;
+
+/**
+  Gives every member the same amount of space
+*/
+pub struct EqualSplit;
+
+/**
+  Returns the layout that a stack in the mode uses
+*/
+pub fn for_mode(mode: &StackMode, size: CollapsedSize) -> Box<dyn StackLayout>
+{
+    match *mode
+    {
+        StackMode::Collapsed => Box::new(Accordion{size: size}),
+        StackMode::Hidden => Box::new(Monocle),
+        StackMode::Neighbours => Box::new(Neighbours),
+        StackMode::Equal => Box::new(EqualSplit)
+    }
+}
+
+impl StackLayout for Accordion
+{
+    fn arrange(&self, stack: &StackTree, focused: u64) -> Arrangement
+    {
+        let member_size = |node: &Node| self.size.subtree_size(node, &stack.direction);
+
+        Arrangement {
+            ratios: expand_member(stack, focused, &member_size, &vec!()),
+            hidden: show_members(stack, |_| true)
+        }
+    }
+}
+
+impl StackLayout for Monocle
+{
+    fn arrange(&self, stack: &StackTree, focused: u64) -> Arrangement
+    {
+        let shown = stack.member_containing(focused);
+
+        Arrangement {
+            ratios: vec!(),
+            hidden: show_members(stack, |index| Some(index) == shown)
+        }
+    }
+}
+
+impl StackLayout for Neighbours
+{
+    fn arrange(&self, stack: &StackTree, focused: u64) -> Arrangement
+    {
+        let expanded = match stack.member_containing(focused)
+        {
+            Some(expanded) => expanded,
+            None => return Arrangement{ratios: vec!(), hidden: show_members(stack, |_| true)}
+        };
+
+        let is_neighbour = |index: usize| index + 1 == expanded || index == expanded + 1;
+
+        let members = stack.members();
+        let neighbours = members.iter()
+            .enumerate()
+            .filter(|&(index, _)| is_neighbour(index))
+            .map(|(_, member)| bspwm::get_node_id(member))
+            .collect::<Vec<_>>();
+        let hidden = members.iter()
+            .enumerate()
+            .filter(|&(index, _)| index != expanded && !is_neighbour(index))
+            .map(|(_, member)| bspwm::get_node_id(member))
+            .collect::<Vec<_>>();
+
+        let extent = bspwm::rectangle_extent(&stack.root.rectangle, &stack.direction);
+        let neighbour_size = match neighbours.len()
+        {
+            0 => 0,
+            count => extent / (2 * count as u64)
+        };
+
+        let member_size = |node: &Node| match neighbours.contains(&bspwm::get_node_id(node))
+        {
+            true => neighbour_size,
+            false => 0
+        };
+
+        Arrangement {
+            ratios: expand_member(stack, focused, &member_size, &hidden),
+            hidden: show_members(stack, |index| index == expanded || is_neighbour(index))
+        }
+    }
+}
+
+impl StackLayout for EqualSplit
+{
+    fn arrange(&self, stack: &StackTree, _focused: u64) -> Arrangement
+    {
+        let mut ratios = vec!();
+        balance_members(stack, stack.root, &mut ratios);
+
+        Arrangement {
+            ratios: ratios,
+            hidden: show_members(stack, |_| true)
+        }
+    }
+}
+
+/**
+  Changes the hidden flags and ratios of the stack to the arrangement right
+  away, stopping any animation of the stack. Members that already have the
+  right flag are left alone.
+
+  The changes are all or nothing: if bspwm fails, the flags and ratios that
+  were already changed are restored
+*/
+pub fn apply(bspc: &dyn Backend, animations: &Animations, stack: &StackTree, arrangement: &Arrangement)
+    -> Result<(), BspwmError>
+{
+    animations.cancel(bspwm::get_node_id(stack.root));
+
+    let changed_flags = apply_hidden(bspc, stack, arrangement)?;
+
+    let previous = bspwm::get_node_ratios(stack.root);
+    if let Err(e) = bspwm::change_ratios(bspc, &arrangement.ratios, &previous)
+    {
+        restore_hidden(bspc, &changed_flags);
+        return Err(e)
+    }
+
+    Ok(())
+}
+
+/**
+  Changes the hidden flags right away and moves to the new ratios over
+  `duration_ms` in the background. The animation replaces any animation
+  that is still running for the stack
+*/
+pub fn animate(
+        bspc: &dyn Backend,
+        animations: &Animations,
+        stack: &StackTree,
+        arrangement: &Arrangement,
+        easing: &Easing,
+        duration_ms: u64
+    ) -> Result<(), BspwmError>
+{
+    let changed_flags = apply_hidden(bspc, stack, arrangement)?;
+
+    let previous = bspwm::get_node_ratios(stack.root);
+    let frames = animation::ratio_frames(&previous, &arrangement.ratios, easing, duration_ms);
+    animations.start(bspc.connect(), bspwm::get_node_id(stack.root), frames, previous, changed_flags);
+
+    Ok(())
+}
+
+/**
+  Changes the hidden flags of the members and returns the ones that changed.
+  If bspwm fails, the flags that were already changed are restored
+*/
+fn apply_hidden(bspc: &dyn Backend, stack: &StackTree, arrangement: &Arrangement)
+    -> Result<Vec<(u64, bool)>, BspwmError>
+{
+    let members = stack.members();
+
+    let changes = arrangement.hidden.iter()
+        .cloned()
+        .filter(|&(id, hidden)| {
+            members.iter()
+                .find(|member| bspwm::get_node_id(member) == id)
+                .map(|member| member.flags.hidden) != Some(hidden)
+        })
+        .collect::<Vec<_>>();
+
+    for (index, &(id, hidden)) in changes.iter().enumerate()
+    {
+        if let Err(e) = bspwm::node_set_hidden(bspc, id, hidden)
+        {
+            restore_hidden(bspc, &changes[..index]);
+            return Err(e)
+        }
+    }
+
+    Ok(changes)
+}
+
+/**
+  Flips the changed hidden flags back. Failures are ignored since there is
+  nothing left to fall back to
+*/
+pub fn restore_hidden(bspc: &dyn Backend, changed: &[(u64, bool)])
+{
+    for &(id, hidden) in changed.iter().rev()
+    {
+        let _ = bspwm::node_set_hidden(bspc, id, !hidden);
+    }
+}
+
+/**
+  Shows every hidden window below the node. Stacks that are forgotten without
+  being cleaned up, because they lost their members, use this so that the
+  windows they hid don't stay hidden
+*/
+pub fn show_hidden_leaves(bspc: &dyn Backend, node: u64) -> Result<(), BspwmError>
+{
+    let root = match bspwm::get_node_tree(bspc, node)
+    {
+        Ok(root) => root,
+        Err(BspwmError::MissingNode(_)) => return Ok(()),
+        Err(e) => return Err(e)
+    };
+
+    for leaf in bspwm::get_node_descendant_leaf_nodes(&root).iter().filter(|leaf| leaf.flags.hidden)
+    {
+        bspwm::node_set_hidden(bspc, bspwm::get_node_id(leaf), false)?;
+    }
+
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//                          Shared layout helpers
+////////////////////////////////////////////////////////////////////////////////
+
+/**
+  Returns the hidden flag of every member, members whose index passes
+  `is_shown` are shown
+*/
+fn show_members<F>(stack: &StackTree, is_shown: F) -> Vec<(u64, bool)>
+    where F: Fn(usize) -> bool
+{
+    stack.members()
+        .iter()
+        .enumerate()
+        .map(|(index, member)| (bspwm::get_node_id(member), !is_shown(index)))
+        .collect()
+}
+
+/**
+  Returns true if every member in the subtree is in `hidden`. bspwm gives
+  such a subtree no space at all
+*/
+fn is_vacant(stack: &StackTree, node: &Node, hidden: &Vec<u64>) -> bool
+{
+    bspwm::find_stack_members(node, &stack.direction, stack.nested_roots)
+        .iter()
+        .all(|member| hidden.contains(&bspwm::get_node_id(member)))
+}
+
+/**
+  The number of pixels that a collapsed subtree needs along the split
+  direction when each member in it needs `member_size`
+*/
+fn collapsed_extent(stack: &StackTree, node: &Node, member_size: &dyn Fn(&Node) -> u64) -> u64
+{
+    match bspwm::get_node_children(node)
+    {
+        Some((first, second)) if !stack.is_member(node) =>
+        {
+            collapsed_extent(stack, first, member_size) + collapsed_extent(stack, second, member_size)
+        }
+        _ => member_size(node)
+    }
+}
+
+/**
+  Returns the ratios that expand the member containing `focused` and give
+  every other member `member_size` pixels. The ratios above vacant subtrees
+  are left alone since bspwm ignores them
+*/
+fn expand_member(stack: &StackTree, focused: u64, member_size: &dyn Fn(&Node) -> u64, hidden: &Vec<u64>)
+    -> Vec<(u64, f32)>
+{
+    let mut ratios = vec!();
+
+    if let Some(path) = bspwm::find_path_to_node(stack.root, focused)
+    {
+        expand_path(stack, stack.root, &stack.root.rectangle, path, member_size, hidden, &mut ratios);
+    }
+
+    ratios
+}
+
+/**
+  Expands the stack member on the path and collapses everything next to it.
+  Only the nodes split along the stack direction are changed, the path ends
+  at the first member.
+  `rectangle` is the area that `node` will occupy once its parents are laid out
+*/
+fn expand_path(
+        stack: &StackTree,
+        node: &Node,
+        rectangle: &Rectangle,
+        mut remaining_path: Vec<Children>,
+        member_size: &dyn Fn(&Node) -> u64,
+        hidden: &Vec<u64>,
+        ratios: &mut Vec<(u64, f32)>
+    )
+{
+    if stack.is_member(node)
+    {
+        return
+    }
+
+    //Find which node should be traversed and which node should be collapsed
+    let (should_collapse_first, traverse_node, collapsed_node) =
+        match (remaining_path.pop(), bspwm::get_node_children(node))
+    {
+        (Some(Children::First), Some((first, second))) => (false, first, second),
+        (Some(Children::Second), Some((first, second))) => (true, second, first),
+        _ => return
+    };
+
+    let traverse_rectangle = match is_vacant(stack, collapsed_node, hidden)
+    {
+        true => rectangle.clone(),
+        false => {
+            let extent = bspwm::rectangle_extent(rectangle, &stack.direction);
+
+            //Calculate the ratio that we need to change the current node to
+            let collapsed_size = collapsed_extent(stack, collapsed_node, member_size);
+
+            let ratio = match should_collapse_first
+            {
+                true => bspwm::pixel_ratio(collapsed_size, extent),
+                false => bspwm::pixel_ratio(extent.saturating_sub(collapsed_size), extent)
+            };
+            ratios.push((bspwm::get_node_id(node), ratio as f32));
+
+            //Work out where the traversed node ends up with the new ratio
+            let fence = (extent as f64 * ratio) as u64;
+            let (first_rectangle, second_rectangle) =
+                bspwm::split_rectangle(rectangle, &stack.direction, fence);
+
+            match should_collapse_first
+            {
+                true => second_rectangle,
+                false => first_rectangle
+            }
+        }
+    };
+
+    collapse_subtree(stack, collapsed_node, member_size, hidden, ratios);
+
+    //Dig deeper
+    expand_path(stack, traverse_node, &traverse_rectangle, remaining_path, member_size, hidden, ratios)
+}
+
+/**
+  Returns the ratios that give every member in a collapsed subtree
+  `member_size` pixels. Members keep their own ratios and nodes with a
+  vacant child are left alone
+*/
+fn collapse_subtree(
+        stack: &StackTree,
+        node: &Node,
+        member_size: &dyn Fn(&Node) -> u64,
+        hidden: &Vec<u64>,
+        ratios: &mut Vec<(u64, f32)>
+    )
+{
+    if stack.is_member(node) || is_vacant(stack, node, hidden)
+    {
+        return
+    }
+
+    if let Some((first, second)) = bspwm::get_node_children(node)
+    {
+        if !is_vacant(stack, first, hidden) && !is_vacant(stack, second, hidden)
+        {
+            let first_size = collapsed_extent(stack, first, member_size);
+            let total_size = first_size + collapsed_extent(stack, second, member_size);
+
+            let ratio = bspwm::pixel_ratio(first_size, total_size);
+            ratios.push((bspwm::get_node_id(node), ratio as f32));
+        }
+
+        collapse_subtree(stack, first, member_size, hidden, ratios);
+        collapse_subtree(stack, second, member_size, hidden, ratios);
+    }
+}
+
+/**
+  Adds the ratios that give every member in the subtree the same amount of
+  space without touching the ratios inside the members. Returns the number
+  of members in the subtree
+*/
+fn balance_members(stack: &StackTree, node: &Node, ratios: &mut Vec<(u64, f32)>) -> u64
+{
+    if stack.is_member(node)
+    {
+        return 1
+    }
+
+    match bspwm::get_node_children(node)
+    {
+        Some((first, second)) =>
+        {
+            let first_members = balance_members(stack, first, ratios);
+            let second_members = balance_members(stack, second, ratios);
+
+            let ratio = first_members as f32 / (first_members + second_members) as f32;
+            ratios.push((bspwm::get_node_id(node), ratio));
+
+            first_members + second_members
+        }
+        None => 1
+    }
+}
+
+
+
+#[cfg(test)]
+mod layout_tests
+{
+    use super::*;
+
+    use backend::MockBackend;
+
+    fn sample_stack() -> Node
+    {
+        bspwm::get_node_tree(&MockBackend::from_sample(), 4194628).unwrap()
+    }
+
+    #[test]
+    fn neighbours_layout_test()
+    {
+        let root = sample_stack();
+        let nested_roots = vec!();
+        let stack = StackTree::new(&root, &nested_roots);
+
+        //The neighbours get a quarter of the 1073 pixels each, the last member
+        //is hidden so the ratio of its parent doesn't matter
+        assert_eq!(Neighbours.arrange(&stack, 29526298), Arrangement {
+                ratios: vec!((4194628, 0.250233), (4194636, 0.66770184)),
+                hidden: vec!((29538275, false), (29526298, false), (4194638, false), (29541363, true))
+            });
+
+        //A hidden first member gives its space to the rest of the stack
+        assert_eq!(Neighbours.arrange(&stack, 29541339), Arrangement {
+                ratios: vec!((4194636, 0.250233), (4194640, 0.66770184)),
+                hidden: vec!((29538275, true), (29526298, false), (4194638, false), (29541363, false))
+            });
+    }
+
+    #[test]
+    fn apply_failure_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let root = sample_stack();
+        let nested_roots = vec!();
+        let stack = StackTree::new(&root, &nested_roots);
+
+        //The second ratio fails, everything that was changed is changed back
+        bspc.fail_commands("0x40014C -r");
+        let result = apply(&bspc, &Animations::new(), &stack, &Neighbours.arrange(&stack, 29526298));
+
+        assert_eq!(result, Err(BspwmError::NonZeroExit("node 0x40014C -r 0.66770184 failed".to_string())));
+        assert_eq!(bspc.commands(), vec!(
+                "node 0x1C2C3F3 -g hidden=on",
+                "node 0x400144 -r 0.250233",
+                "node 0x400144 -r 0.5",
+                "node 0x1C2C3F3 -g hidden=off",
+            ));
+    }
+
+    #[test]
+    fn equal_and_monocle_layout_test()
+    {
+        let root = sample_stack();
+        let nested_roots = vec!(4194640);
+        let stack = StackTree::new(&root, &nested_roots);
+
+        //The nested stack is a single member
+        assert_eq!(EqualSplit.arrange(&stack, 29526298), Arrangement {
+                ratios: vec!((4194636, 0.5), (4194628, 0.33333334)),
+                hidden: vec!((29538275, false), (29526298, false), (4194640, false))
+            });
+
+        assert_eq!(Monocle.arrange(&stack, 29541339), Arrangement {
+                ratios: vec!(),
+                hidden: vec!((29538275, true), (29526298, true), (4194640, false))
+            });
+    }
+}
@@ -1,25 +1,54 @@
 #![allow(dead_code)]
 
+extern crate clap;
 extern crate regex;
 extern crate rustc_serialize;
 extern crate notify_rust;
 extern crate typed_messages;
-
-use rustc_serialize::json;
+extern crate libc;
 
 use std::vec::Vec;
 
 use std::time::Duration;
-
-use notify_rust::Notification;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::os::unix::net::UnixStream;
+use std::os::unix::io::FromRawFd;
+use std::fs::File;
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::process;
 
 mod bspwm;
 mod subprogram;
 mod messages;
+mod backend;
+mod state;
+mod transport;
+mod tabs;
+mod config;
+mod layout;
+mod animation;
+mod notify;
+mod hooks;
+
+use messages::{Command, CommandResponse, StackInfo};
+use backend::Backend;
+use bspwm::BspwmError;
+use transport::Transport;
+use tabs::{Tab, StackTabs, TabBar};
+use config::{Config, StackSettings, StackMode};
+use layout::StackLayout;
+use animation::Animations;
+use notify::Event;
 
-use messages::{Command, CommandResponse};
+use rustc_serialize::json;
+
+use clap::{App, Arg};
 
-const TIMEOUT_SECONDS: u64 = 1;
+const EVENT_RETRY_SECONDS: u64 = 1;
 
 ////////////////////////////////////////////////////////////////////////////////
 //                         Bspc calls
@@ -29,10 +58,16 @@ const TIMEOUT_SECONDS: u64 = 1;
 /**
     Struct that keeps track of a window stack
 */
-#[derive(Clone, RustcEncodable)]
+#[derive(Clone, PartialEq, Debug, RustcEncodable, RustcDecodable)]
 struct StackState 
 {
-    pub root: u64
+    pub root: u64,
+    /// The leaf that was last expanded by the stack
+    pub expanded: Option<u64>,
+    pub mode: StackMode,
+    /// The children of the root when the stack was last looked at. When one
+    /// of them is removed, bspwm replaces the root with the other one
+    pub children: Option<(u64, u64)>
 }
 
 #[derive(Debug)]
@@ -57,30 +92,62 @@ impl StackState
       Creates a new stack containing all the child nodes of the current focused
       node
     */
-    pub fn new(root_json: &json::Object) -> StackState
+    pub fn new(root: &bspwm::Node, mode: StackMode) -> StackState
     {
         StackState{
-            root: bspwm::get_node_id(&root_json)
+            root: bspwm::get_node_id(root),
+            expanded: None,
+            mode: mode,
+            children: bspwm::get_node_children(root)
+                .map(|(first, second)| (bspwm::get_node_id(first), bspwm::get_node_id(second)))
         }
     }
 
     /**
-      Tries to focus the indexth leaf in the tree. Returns None if the index
-      is out of bounds, Some(id) where id is the id of the focused node if successfull
+      Tries to focus the indexth member of the stack. Returns None if the index
+      is out of bounds, Some(id) where id is the id of the focused leaf if successfull
+
+      `stack_roots` are the roots of all the stacks, the layout of this stack
+      stops at the stacks that are nested in it
     */
-    pub fn focus_leaf_by_index(&self, index: usize) -> Option<u64>
+    pub fn focus_member_by_index(&mut self, bspc: &dyn Backend, config: &Config, animations: &Animations, stack_roots: &Vec<u64>, index: usize)
+        -> Result<Option<u64>, BspwmError>
     {
-        let leaves = bspwm::get_node_descendant_leaves(&bspwm::get_node_json(self.root));
+        let root = bspwm::get_node_tree(bspc, self.root)?;
+
+        let leaf = self.members(&root, stack_roots)
+            .get(index)
+            .map(|member| self.member_leaf(member));
 
-        if index < leaves.len()
+        match leaf
         {
-            let id = leaves[index];
-            self.focus_node_by_id(id);
-            Some(id)
+            Some(id) => {
+                self.focus_node_by_id(bspc, config, animations, stack_roots, id)?;
+                Ok(Some(id))
+            }
+            None => Ok(None)
         }
-        else
+    }
+
+    /**
+      Returns the members of the stack in order. Subtrees that are split the
+      other way than the stack root and nested stacks are single members
+    */
+    fn members<'a>(&self, root: &'a bspwm::Node, stack_roots: &Vec<u64>) -> Vec<&'a bspwm::Node>
+    {
+        bspwm::find_stack_members(root, &bspwm::get_node_split_direction(root), &self.nested_roots(stack_roots))
+    }
+
+    /**
+      The leaf that is focused when a member is focused. That is the expanded
+      leaf if it is part of the member, otherwise the first leaf of the member
+    */
+    fn member_leaf(&self, member: &bspwm::Node) -> u64
+    {
+        match self.expanded.filter(|expanded| bspwm::is_node_descendant(member, *expanded))
         {
-            None
+            Some(expanded) => expanded,
+            None => bspwm::get_node_descendant_leaves(member)[0]
         }
     }
 
@@ -90,51 +157,152 @@ impl StackState
 
       TODO: If a parent node of the specified node is part of the stack, focus
       it  instead
-      TODO: Currently this focuses nodes through differing split directions
     */
-    fn focus_node_by_id(&self, id: u64)
+    fn focus_node_by_id(&mut self, bspc: &dyn Backend, config: &Config, animations: &Animations, stack_roots: &Vec<u64>, id: u64)
+        -> Result<(), BspwmError>
     {
-        let root_json = &bspwm::get_node_json(self.root);
+        if self.layout_node_by_id(bspc, config, animations, stack_roots, id)?
+        {
+            //Focus the actual node
+            bspwm::node_focus(bspc, id)?;
+        }
+
+        Ok(())
+    }
+
+    /**
+      Expands the stack member containing the specified node without focusing
+      it, using the layout of the stack mode. Subtrees that are split the other
+      way than the stack root are single members, like in `find_target_stack`,
+      and so are nested stacks. Returns false if the node is not part of the stack.
 
-        //Finding the 'path' to the target node
-        let path = bspwm::find_path_to_node(root_json, id);
+      Stacks that have other stacks nested in them are never animated
+    */
+    fn layout_node_by_id(&mut self, bspc: &dyn Backend, config: &Config, animations: &Animations, stack_roots: &Vec<u64>, id: u64)
+        -> Result<bool, BspwmError>
+    {
+        let root = bspwm::get_node_tree(bspc, self.root)?;
 
-        if path.is_none()
+        if !bspwm::is_node_descendant(&root, id)
         {
-            return
+            return Ok(false)
         }
 
-        let direction = bspwm::get_node_split_direction(&root_json);
+        let size = bspwm::CollapsedSize {
+            window_size: stack_settings(bspc, config, self.root).collapsed_window_size,
+            window_gap: bspwm::get_node_window_gap(bspc, self.root)?
+        };
+
+        let nested_roots = self.nested_roots(stack_roots);
+        let tree = layout::StackTree::new(&root, &nested_roots);
 
-        //Getting the correct directions for the resizing
-        let resize_directions = match direction
+        //The stacks nested in this one are laid out for where it ends up, so
+        //only stacks without nested stacks are animated
+        let has_nested_stacks = nested_roots.iter().any(|nested| bspwm::is_node_descendant(&root, *nested));
+
+        let arrangement = layout::for_mode(&self.mode, size).arrange(&tree, id);
+        match (config.animation_duration_ms, has_nested_stacks)
         {
-            bspwm::SplitDirection::Horizontal => 
-            {
-                (bspwm::ResizeDirection::Top, bspwm::ResizeDirection::Bottom)
-            },
-            _ => 
-            {
-                (bspwm::ResizeDirection::Right, bspwm::ResizeDirection::Left)
-            }
+            (0, _) | (_, true) => layout::apply(bspc, animations, &tree, &arrangement)?,
+            (duration, false) => layout::animate(bspc, animations, &tree, &arrangement, &config.animation_easing, duration)?
+        }
+
+        self.expanded = Some(id);
+        Ok(true)
+    }
+
+    /**
+      Lays out the stack again after its members changed. The expanded leaf
+      stays expanded if it is still part of the stack, otherwise the first
+      leaf is expanded
+    */
+    fn relayout(&mut self, bspc: &dyn Backend, config: &Config, animations: &Animations, stack_roots: &Vec<u64>)
+        -> Result<(), BspwmError>
+    {
+        let expanded_in_stack = match self.expanded
+        {
+            Some(expanded) => self.layout_node_by_id(bspc, config, animations, stack_roots, expanded)?,
+            None => false
         };
 
-        bspwm::focus_node_by_path(root_json, path.unwrap(), &resize_directions);
+        if !expanded_in_stack
+        {
+            let root = bspwm::get_node_tree(bspc, self.root)?;
+            let first_leaf = bspwm::get_node_descendant_leaves(&root)[0];
+
+            self.expanded = None;
+            self.layout_node_by_id(bspc, config, animations, stack_roots, first_leaf)?;
+        }
+
+        Ok(())
+    }
+
+    /**
+      Describes the stack for `rspc stack list`
+    */
+    fn info(&self, bspc: &dyn Backend, stack_roots: &Vec<u64>) -> Result<StackInfo, BspwmError>
+    {
+        let root = bspwm::get_node_tree(bspc, self.root)?;
+
+        let members = self.members(&root, stack_roots).into_iter()
+            .map(bspwm::get_node_descendant_leaves)
+            .collect();
+
+        Ok(StackInfo {
+            root: self.root,
+            desktop: bspwm::get_node_desktop(bspc, self.root).ok(),
+            desktop_name: bspwm::get_node_desktop_name(bspc, self.root).ok(),
+            monitor: bspwm::get_node_monitor(bspc, self.root).ok(),
+            monitor_name: bspwm::get_node_monitor_name(bspc, self.root).ok(),
+            split: bspwm::get_node_split_direction(&root),
+            leaves: bspwm::get_node_descendant_leaves(&root),
+            members: members,
+            expanded: self.expanded
+        })
+    }
+
+    fn contains_node(&self, bspc: &dyn Backend, id: u64) -> bool
+    {
+        match bspwm::get_node_tree(bspc, self.root)
+        {
+            Ok(root) => bspwm::is_node_descendant(&root, id),
+            Err(_) => false
+        }
+    }
 
-        //Focus the actual node
-        bspwm::node_focus(id);
+    /**
+      Changes the mode of the stack and lays it out again
+    */
+    fn set_mode(&mut self, bspc: &dyn Backend, config: &Config, animations: &Animations, stack_roots: &Vec<u64>, mode: StackMode)
+        -> Result<(), BspwmError>
+    {
+        self.mode = mode;
+        self.relayout(bspc, config, animations, stack_roots)
     }
 
-    fn contains_node(&self, id: u64) -> bool
+    /**
+      Shows the hidden members and gives the members of the stack the same
+      amount of space again. Nested stacks are left as they are
+    */
+    fn cleanup(&self, bspc: &dyn Backend, animations: &Animations, stack_roots: &Vec<u64>) -> Result<(), BspwmError>
     {
-        let root_json = bspwm::get_node_json(self.root);
+        let root = bspwm::get_node_tree(bspc, self.root)?;
 
-        bspwm::is_node_descendant(&root_json, id)
+        let nested_roots = self.nested_roots(stack_roots);
+        let tree = layout::StackTree::new(&root, &nested_roots);
+
+        layout::apply(bspc, animations, &tree, &layout::EqualSplit.arrange(&tree, self.root))
     }
 
-    fn cleanup(&self)
+    /**
+      The stack roots that the layout of this stack should leave alone
+    */
+    fn nested_roots(&self, stack_roots: &Vec<u64>) -> Vec<u64>
     {
-        bspwm::node_balance(self.root);
+        stack_roots.iter()
+            .cloned()
+            .filter(|root| *root != self.root)
+            .collect()
     }
 }
 
@@ -169,193 +337,1948 @@ fn cardinal_to_focus_direction
     }
 }
 
-fn remove_stack_containing_node(stack_vec: &mut Vec<StackState>, id: u64) -> CommandResponse
+/**
+    Returns the roots of all the stacks
+ */
+fn stack_roots(stack_vec: &Vec<StackState>) -> Vec<u64>
 {
-    // Find all the stacks that contain the current node as a child.
-    // The stacks are stored as (index, stack) to all
-    let target_index = 
-    {
-        let (_, mut matching_stacks) = stack_vec.iter()
-            .fold(
-                (0, vec!()),
-                |accumulator, stack|
-                {
-                    let (index, mut stacks) = accumulator;
-                    if stack.contains_node(id)
-                    {
-                        stacks.push((index, stack));
-                    }
-                    (index + 1, stacks)
-                }
-            );
+    stack_vec.iter().map(|stack| stack.root).collect()
+}
 
-        if matching_stacks.len() > 0
-        {
-            //Check which stack is at the bottom of all the stacks
-            //that contain the actual target
-            let first_match = matching_stacks.pop().unwrap();
+/**
+    Returns the indexes of the stacks that contain the node, ordered from the
+    outermost stack to the innermost one.
 
-            let target = matching_stacks.into_iter().fold(
-                first_match, 
-                |best_match, stack|
-                {
-                    let (index, stack) = stack;
-                    let (best_index, best_stack) = best_match;
+    The stacks form a hierarchy through the bspwm tree: a stack is nested in
+    every stack that contains its root. All the stacks containing the node are
+    on the path from the bspwm root to the node, so the number of them that
+    contain the root of a stack is its depth
+ */
+fn stacks_containing_node(bspc: &dyn Backend, stack_vec: &Vec<StackState>, id: u64) -> Vec<usize>
+{
+    let matching_stacks = stack_vec.iter()
+        .enumerate()
+        .filter(|&(_, stack)| stack.contains_node(bspc, id))
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
 
-                    match best_stack.contains_node(stack.root)
-                    {
-                        true => (index, stack),
-                        false => (best_index, best_stack)
-                    }
-                });
+    let mut by_depth = matching_stacks.iter()
+        .map(|index| {
+            let depth = matching_stacks.iter()
+                .filter(|other| stack_vec[**other].contains_node(bspc, stack_vec[*index].root))
+                .count();
 
-            let (target_index, _) = target;
-            Some(target_index)
-        }
-        else
-        {
-            println!("No stack removed");
-            None
-        }
-    };
+            (depth, *index)
+        })
+        .collect::<Vec<_>>();
+
+    by_depth.sort();
+
+    by_depth.into_iter().map(|(_, index)| index).collect()
+}
+
+/**
+    Returns the indexes of all the stacks, with the stacks that are nested in
+    others after the stacks that they are nested in
+ */
+fn outermost_first(bspc: &dyn Backend, stack_vec: &Vec<StackState>) -> Vec<usize>
+{
+    let mut by_depth = stack_vec.iter()
+        .enumerate()
+        .map(|(index, stack)| (stacks_containing_node(bspc, stack_vec, stack.root).len(), index))
+        .collect::<Vec<_>>();
+
+    by_depth.sort();
+
+    by_depth.into_iter().map(|(_, index)| index).collect()
+}
+
+/**
+    Returns the index of the innermost stack that contains the node
+ */
+fn find_innermost_stack(bspc: &dyn Backend, stack_vec: &Vec<StackState>, id: u64) -> Option<usize>
+{
+    stacks_containing_node(bspc, stack_vec, id).pop()
+}
+
+fn remove_stack_containing_node(bspc: &dyn Backend, animations: &Animations, stack_vec: &mut Vec<StackState>, id: u64)
+    -> Result<CommandResponse, BspwmError>
+{
+    let target_index = find_innermost_stack(bspc, stack_vec, id);
 
     if target_index.is_some()
     {
         let target_index = target_index.unwrap();
 
-        stack_vec[target_index].cleanup();
-        stack_vec.remove(target_index);
-        CommandResponse::Done
+        //The stack is forgotten even if bspwm fails to balance it
+        let stack = stack_vec.remove(target_index);
+        stack.cleanup(bspc, animations, &stack_roots(stack_vec))?;
+        Ok(CommandResponse::Done)
     }
     else
     {
-        CommandResponse::NoStackExists
+        println!("No stack removed");
+        Ok(CommandResponse::NoStackExists)
     }
 }
 
-fn is_node_in_stacks(stacks: &Vec<StackState>, node: u64) -> bool
+/**
+    Moves the focus within the innermost stack containing the focused node.
+
+    Moving along the split direction of the stack expands the previous or next
+    leaf, moving across it is left to bspwm
+ */
+fn move_in_stack(
+        bspc: &dyn Backend,
+        config: &Config,
+        animations: &Animations,
+        stack_vec: &mut Vec<StackState>,
+        focused: u64,
+        direction: &bspwm::CardinalDirection
+    ) -> Result<CommandResponse, BspwmError>
 {
-    stacks.iter()
-        .fold(false, |acc, stack|{acc || stack.contains_node(node)})
+    let roots = stack_roots(stack_vec);
+    let stack = match find_innermost_stack(bspc, stack_vec, focused)
+    {
+        Some(index) => &mut stack_vec[index],
+        None => return Ok(CommandResponse::NoStackExists)
+    };
+
+    let root = bspwm::get_node_tree(bspc, stack.root)?;
+
+    let focus_direction = cardinal_to_focus_direction(
+            direction,
+            &bspwm::get_node_split_direction(&root)
+        );
+
+    let focus_direction = match focus_direction
+    {
+        Some(focus_direction) => focus_direction,
+        None => return Ok(CommandResponse::UseBspwmFocus)
+    };
+
+    //The focused node might not be in a member, in which case we move from
+    //the expanded one
+    let members = stack.members(&root, &roots);
+    let member_containing = |node: u64| members.iter().position(|member| bspwm::is_node_descendant(member, node));
+    let current = member_containing(focused)
+        .or(stack.expanded.and_then(&member_containing))
+        .unwrap_or(0);
+
+    let target = match focus_direction
+    {
+        FocusDirection::Next => Some(current + 1),
+        FocusDirection::Prev => current.checked_sub(1)
+    };
+
+    let focused_leaf = match target
+    {
+        Some(index) => stack.focus_member_by_index(bspc, config, animations, &roots, index)?,
+        None => None
+    };
+
+    match focused_leaf
+    {
+        Some(_) => Ok(CommandResponse::Done),
+        None => Ok(CommandResponse::EndOfStack)
+    }
 }
 
-fn do_update_stacks(stacks: &mut Vec<StackState>) -> CommandResponse
+/**
+    Returns the child of the stack root that does not contain the node, if
+    the node is a direct child of the root. This is the node that replaces the
+    root in the bspwm tree when the node is moved away
+ */
+fn root_replacement(bspc: &dyn Backend, stack: &StackState, node: u64)
+    -> Result<Option<u64>, BspwmError>
 {
-    let mut stacks_to_remove = vec!();
+    if bspwm::get_node_parent(bspc, node)? != Some(stack.root)
+    {
+        return Ok(None)
+    }
 
-    for i in 0..stacks.len()
+    let root = bspwm::get_node_tree(bspc, stack.root)?;
+
+    Ok(bspwm::get_node_children(&root)
+        .map(|(first, second)| {
+            match bspwm::get_node_id(first) == node
+            {
+                true => bspwm::get_node_id(second),
+                false => bspwm::get_node_id(first)
+            }
+        }))
+}
+
+/**
+    Moves a node into the innermost stack that contains the focused node. The
+    node is inserted before the member at `position`, or after the last member
+    if there is no position or it is out of bounds
+ */
+fn add_to_stack(
+        bspc: &dyn Backend,
+        config: &Config,
+        animations: &Animations,
+        stack_vec: &mut Vec<StackState>,
+        focused: u64,
+        node: u64,
+        position: Option<usize>
+    ) -> Result<CommandResponse, BspwmError>
+{
+    let roots = stack_roots(stack_vec);
+    let stack = match find_innermost_stack(bspc, stack_vec, focused)
     {
-        if !bspwm::get_node_exists(stacks[i].root)
-        {
-            stacks_to_remove.push(i);
-        }
+        Some(index) => &mut stack_vec[index],
+        None => return Ok(CommandResponse::NoStackExists)
+    };
+
+    let root = bspwm::get_node_tree(bspc, stack.root)?;
+
+    let members = stack.members(&root, &roots);
+    let (before, after) = bspwm::get_node_split_direction(&root).child_directions();
+
+    let (target, direction) = match position
+    {
+        Some(index) if index < members.len() => (bspwm::get_node_id(members[index]), before),
+        _ => (bspwm::get_node_id(members[members.len() - 1]), after)
+    };
+
+    if target == node
+    {
+        return Ok(CommandResponse::Done)
     }
 
-    //Move backwards to remove the nodes in order to avoid having to recalculate
-    //the indexes
-    stacks_to_remove.reverse();
+    //Moving a direct child of the root out of it replaces the root
+    let new_root = root_replacement(bspc, stack, node)?;
 
-    for index in stacks_to_remove
+    bspwm::node_preselect(bspc, target, &direction)?;
+    bspwm::node_transfer(bspc, node, target)?;
+
+    if let Some(new_root) = new_root
     {
-        stacks.remove(index);
-        println!("Removing stack {}", index);
+        stack.root = new_root;
     }
 
-    CommandResponse::Done
+    stack.relayout(bspc, config, animations, &roots)?;
+    Ok(CommandResponse::Done)
 }
 
-fn try_notify(summary: &str, body: &str, timeout: u64)
+/**
+    Moves the focused node out of the innermost stack containing it. It is
+    placed next to the stack root, after the stack
+ */
+fn eject_from_stack(bspc: &dyn Backend, config: &Config, animations: &Animations, stack_vec: &mut Vec<StackState>, focused: u64)
+    -> Result<CommandResponse, BspwmError>
 {
-    match Notification::new()
-        .summary(summary)
-        .body(body)
-        .timeout(timeout as i32)
-        .show()
+    let index = match find_innermost_stack(bspc, stack_vec, focused)
+    {
+        Some(index) if stack_vec[index].root != focused => index,
+        _ => return Ok(CommandResponse::NoStackExists)
+    };
+
+    match root_replacement(bspc, &stack_vec[index], focused)?
+    {
+        //The focused node is already next to the rest of the stack, the
+        //remaining members become the new stack
+        Some(new_root) => {
+            let old_root = bspwm::get_node_name(stack_vec[index].root);
+            bspwm::node_change_ratio(bspc, &old_root, 0.5)?;
+            stack_vec[index].root = new_root;
+        }
+        None => {
+            let root = stack_vec[index].root;
+            let tree = bspwm::get_node_tree(bspc, root)?;
+            let direction = bspwm::get_node_split_direction(&tree).child_directions().1;
+
+            bspwm::node_preselect(bspc, root, &direction)?;
+            bspwm::node_transfer(bspc, focused, root)?;
+        }
+    }
 
+    //A stack with a single window is no stack
+    if is_single_window(bspc, stack_vec[index].root)?
+    {
+        layout::show_hidden_leaves(bspc, stack_vec[index].root)?;
+        stack_vec.remove(index);
+    }
+    else
     {
-        _ => {},
+        let roots = stack_roots(stack_vec);
+        stack_vec[index].relayout(bspc, config, animations, &roots)?;
     }
+
+    bspwm::node_focus(bspc, focused)?;
+    Ok(CommandResponse::Done)
 }
 
-fn main() 
+/**
+    Swaps the member of the innermost stack containing the focused node with
+    the previous or next member
+ */
+fn reorder_in_stack(
+        bspc: &dyn Backend,
+        config: &Config,
+        animations: &Animations,
+        stack_vec: &mut Vec<StackState>,
+        focused: u64,
+        direction: &bspwm::CardinalDirection
+    ) -> Result<CommandResponse, BspwmError>
 {
-    let mut stacks = vec!();
+    let roots = stack_roots(stack_vec);
+    let stack = match find_innermost_stack(bspc, stack_vec, focused)
+    {
+        Some(index) => &mut stack_vec[index],
+        None => return Ok(CommandResponse::NoStackExists)
+    };
 
-    println!("Running rspwm server. Control using rspc");
+    let root = bspwm::get_node_tree(bspc, stack.root)?;
+    let split = bspwm::get_node_split_direction(&root);
 
-    let command_handler = |command: Command|
+    let focus_direction = match cardinal_to_focus_direction(direction, &split)
     {
-        match command
-        {
-            Command::CreateStack => {
-                match bspwm::get_focused_node()
-                {
-                    Some(node) => {
-                        let stack = StackState::new(&bspwm::get_node_json(node));
-                        stack.focus_leaf_by_index(0);
-                        stacks.push(stack);
+        Some(focus_direction) => focus_direction,
+        None => return Ok(CommandResponse::UseBspwmFocus)
+    };
 
-                        try_notify("Stack created", "", 2000);
-                    }
-                    None => {
-                        try_notify("No focused window", "", 2000);
-                    }
-                };
-                CommandResponse::Done
-            },
-            Command::RemoveFocused => {
-                match bspwm::get_focused_node()
-                {
-                    Some(focused) => {
-                        try_notify("Stack removed", "", 2000);
+    let members = bspwm::find_stack_members(&root, &split, &stack.nested_roots(&roots));
 
-                        remove_stack_containing_node(&mut stacks, focused)
-                    }
-                    None => CommandResponse::Done
-                }
-            },
-            Command::IsFocusedInStack => {
-                let focused = match bspwm::get_focused_node()
-                {
-                    Some(node) => node,
-                    None => {return CommandResponse::No}
-                };
+    let current = match members.iter().position(|member| bspwm::is_node_descendant(member, focused))
+    {
+        Some(current) => current,
+        None => return Ok(CommandResponse::NoStackExists)
+    };
 
-                match is_node_in_stacks(&stacks, focused)
-                {
-                    true => CommandResponse::Yes,
-                    false => CommandResponse::No
-                }
-            },
-            Command::FocusCurrent => {
-                do_update_stacks(&mut stacks);
+    let target = match focus_direction
+    {
+        FocusDirection::Next => Some(current + 1),
+        FocusDirection::Prev => current.checked_sub(1)
+    };
 
-                match bspwm::get_focused_node()
-                {
-                    Some(node) => {
-                        for stack in &stacks
-                        {
-                            stack.focus_node_by_id(node)
-                        }
-                    },
-                    None => {}
-                }
+    match target.and_then(|target| members.get(target))
+    {
+        Some(target) => {
+            let current = bspwm::get_node_id(members[current]);
+            bspwm::node_swap(bspc, current, bspwm::get_node_id(target))?
+        }
+        None => return Ok(CommandResponse::EndOfStack)
+    }
 
+    stack.expanded = Some(focused);
+    stack.relayout(bspc, config, animations, &roots)?;
+    bspwm::node_focus(bspc, focused)?;
 
-                CommandResponse::Done
-            }
-            Command::UpdateStacks => {
-                do_update_stacks(&mut stacks)
+    Ok(CommandResponse::Done)
+}
+
+/**
+    Lays out the stacks with the specified roots again, outermost first.
+    Stacks that are left with a single window are removed, and the window is
+    shown if the stack hid it
+ */
+fn relayout_stacks(bspc: &dyn Backend, config: &Config, animations: &Animations, stack_vec: &mut Vec<StackState>, changed: &Vec<u64>)
+    -> Result<(), BspwmError>
+{
+    let roots = stack_roots(stack_vec);
+    let mut single_windows = vec!();
+
+    for index in outermost_first(bspc, stack_vec)
+    {
+        if !changed.contains(&stack_vec[index].root)
+        {
+            continue
+        }
+
+        match is_single_window(bspc, stack_vec[index].root)?
+        {
+            true => {
+                layout::show_hidden_leaves(bspc, stack_vec[index].root)?;
+                single_windows.push(stack_vec[index].root)
             }
+            false => stack_vec[index].relayout(bspc, config, animations, &roots)?
         }
-    };
+    }
 
-    let timeout = Some(Duration::new(TIMEOUT_SECONDS, 0));
-    typed_messages::run_read_reply_server(9232, command_handler, timeout).unwrap();
+    stack_vec.retain(|stack| !single_windows.contains(&stack.root));
+    Ok(())
 }
 
+/**
+    Moves the focused node to the target of a directional move, or swaps it
+    with the target. The stack that the node leaves and the stack that it
+    enters are both laid out again
+ */
+fn move_focused(
+        bspc: &dyn Backend,
+        config: &Config,
+        animations: &Animations,
+        stack_vec: &mut Vec<StackState>,
+        focused: u64,
+        target: &bspwm::FocusTarget,
+        swap: bool
+    ) -> Result<CommandResponse, BspwmError>
+{
+    let target_node = match *target
+    {
+        bspwm::FocusTarget::Node(node) => Some(node),
+        bspwm::FocusTarget::Desktop(_) => None,
+        bspwm::FocusTarget::None => return Ok(CommandResponse::NoSuchNode)
+    };
+
+    let source_index = find_innermost_stack(bspc, stack_vec, focused)
+        .filter(|index| stack_vec[*index].root != focused);
+    let target_index = target_node.and_then(|node| find_innermost_stack(bspc, stack_vec, node));
+
+    //Moving a direct child of the root out of it replaces the root, swapping
+    //leaves the tree as it is
+    let new_root = match (source_index, swap)
+    {
+        (Some(index), false) => root_replacement(bspc, &stack_vec[index], focused)?,
+        _ => None
+    };
+
+    //Windows that are moved into a stack are placed along it
+    if let (Some(index), Some(node), false) = (target_index, target_node, swap)
+    {
+        let tree = bspwm::get_node_tree(bspc, stack_vec[index].root)?;
+        let (_, after) = bspwm::get_node_split_direction(&tree).child_directions();
+
+        bspwm::node_preselect(bspc, node, &after)?;
+    }
+
+    bspwm::move_to_target(bspc, focused, target, swap)?;
 
+    if let Some(index) = source_index
+    {
+        if let Some(new_root) = new_root
+        {
+            stack_vec[index].root = new_root;
+        }
+    }
+    if let Some(index) = target_index
+    {
+        stack_vec[index].expanded = Some(focused);
+    }
+
+    let changed = source_index.into_iter()
+        .chain(target_index)
+        .map(|index| stack_vec[index].root)
+        .collect();
+
+    do_update_stacks(bspc, stack_vec)?;
+    relayout_stacks(bspc, config, animations, stack_vec, &changed)?;
 
+    bspwm::node_focus(bspc, focused)?;
+    Ok(CommandResponse::Done)
+}
+
+fn is_node_in_stacks(bspc: &dyn Backend, stacks: &Vec<StackState>, node: u64) -> bool
+{
+    stacks.iter()
+        .fold(false, |acc, stack|{acc || stack.contains_node(bspc, node)})
+}
+
+/**
+    Returns true if the node is a single window or no longer exists
+ */
+fn is_single_window(bspc: &dyn Backend, node: u64) -> Result<bool, BspwmError>
+{
+    match bspwm::get_node_tree(bspc, node)
+    {
+        Ok(root) => Ok(bspwm::get_node_children(&root).is_none()),
+        Err(BspwmError::MissingNode(_)) => Ok(true),
+        Err(e) => Err(e)
+    }
+}
 
+/**
+    Updates the stacks whose root no longer exists. If only one of the
+    children of the root is left, it took the place of the root in the bspwm
+    tree and becomes the new root. Other stacks are removed, and the windows
+    that they hid are shown again. Nothing is removed if bspwm can't be asked
+ */
+fn do_update_stacks(bspc: &dyn Backend, stacks: &mut Vec<StackState>)
+    -> Result<CommandResponse, BspwmError>
+{
+    let mut stacks_to_remove = vec!();
 
+    for i in 0..stacks.len()
+    {
+        if bspwm::get_node_exists(bspc, stacks[i].root)?
+        {
+            continue
+        }
 
+        let mut remaining = vec!();
+        for &(first, second) in stacks[i].children.iter()
+        {
+            for child in vec!(first, second)
+            {
+                if bspwm::get_node_exists(bspc, child)?
+                {
+                    remaining.push(child);
+                }
+            }
+        }
+
+        match remaining.as_slice()
+        {
+            &[child] if !is_single_window(bspc, child)? && !stacks.iter().any(|stack| stack.root == child) => {
+                stacks[i].root = child;
+            }
+            _ => {
+                for child in remaining
+                {
+                    layout::show_hidden_leaves(bspc, child)?;
+                }
+                stacks_to_remove.push(i);
+            }
+        }
+    }
+
+    //Move backwards to remove the nodes in order to avoid having to recalculate
+    //the indexes
+    stacks_to_remove.reverse();
+
+    for index in stacks_to_remove
+    {
+        stacks.remove(index);
+        println!("Removing stack {}", index);
+    }
+
+    Ok(CommandResponse::Done)
+}
+
+/**
+    Returns the settings for a stack based on the desktop and monitor it is on
+*/
+fn stack_settings(bspc: &dyn Backend, config: &Config, node: u64) -> StackSettings
+{
+    let desktop = bspwm::get_node_desktop_name(bspc, node).ok();
+    let monitor = bspwm::get_node_monitor_name(bspc, node).ok();
+
+    config.stack_settings(
+            desktop.as_ref().map(|name| name.as_str()),
+            monitor.as_ref().map(|name| name.as_str())
+        )
+}
+
+/**
+    Describes all stacks. Stacks that can't be described are left out
+*/
+fn stack_infos(bspc: &dyn Backend, stacks: &Vec<StackState>) -> Vec<StackInfo>
+{
+    let roots = stack_roots(stacks);
+
+    stacks.iter()
+        .filter_map(|stack| {
+            match stack.info(bspc, &roots)
+            {
+                Ok(info) => Some(info),
+                Err(msg) => {
+                    println!("{}", msg);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/**
+    Runs a command from rspc or from the event loop against the current stacks.
+    Failures in bspwm are reported back as CommandResponse::Failed
+*/
+fn handle_command(bspc: &dyn Backend, config: &Config, animations: &Animations, stacks: &mut Vec<StackState>, command: Command)
+    -> CommandResponse
+{
+    let response = match try_handle_command(bspc, config, animations, stacks, command)
+    {
+        Ok(response) => response,
+        Err(e) => {
+            println!("{}", e);
+            CommandResponse::Failed(e)
+        }
+    };
+
+    remember_root_children(bspc, stacks);
+    response
+}
+
+/**
+    Records the children of every stack root, which is where the replacement
+    of a removed root is found. Stacks that bspwm can't be asked about keep
+    what was recorded before
+ */
+fn remember_root_children(bspc: &dyn Backend, stacks: &mut Vec<StackState>)
+{
+    for stack in stacks.iter_mut()
+    {
+        if let Ok(root) = bspwm::get_node_tree(bspc, stack.root)
+        {
+            stack.children = bspwm::get_node_children(&root)
+                .map(|(first, second)| (bspwm::get_node_id(first), bspwm::get_node_id(second)));
+        }
+    }
+}
+
+fn try_handle_command(bspc: &dyn Backend, config: &Config, animations: &Animations, stacks: &mut Vec<StackState>, command: Command)
+    -> Result<CommandResponse, BspwmError>
+{
+    match command
+    {
+        Command::CreateStack(mode) => {
+            match bspwm::get_focused_node(bspc)?
+            {
+                //Stacks can be nested in other stacks, but a node can only
+                //be the root of one of them
+                Some(node) if stacks.iter().any(|stack| stack.root == node) => {
+                    notify::notify(config, Event::AlreadyAStack);
+                    return Ok(CommandResponse::StackExists)
+                }
+                Some(node) => {
+                    let root = bspwm::get_node_tree(bspc, node)?;
+
+                    let settings = stack_settings(bspc, config, node);
+
+                    let mut stack = StackState::new(&root, mode.unwrap_or(settings.mode));
+                    if settings.focus_first_leaf_on_create
+                    {
+                        stack.focus_member_by_index(bspc, config, animations, &stack_roots(stacks), 0)?;
+                    }
+                    stacks.push(stack);
+
+                    notify::notify(config, Event::StackCreated);
+                }
+                None => {
+                    notify::notify(config, Event::NoFocusedWindow);
+                }
+            };
+            Ok(CommandResponse::Done)
+        },
+        Command::RemoveFocused => {
+            match bspwm::get_focused_node(bspc)?
+            {
+                Some(focused) => {
+                    notify::notify(config, Event::StackRemoved);
+
+                    remove_stack_containing_node(bspc, animations, stacks, focused)
+                }
+                None => Ok(CommandResponse::Done)
+            }
+        },
+        Command::IsFocusedInStack => {
+            let focused = match bspwm::get_focused_node(bspc)?
+            {
+                Some(node) => node,
+                None => {return Ok(CommandResponse::No)}
+            };
+
+            match is_node_in_stacks(bspc, stacks, focused)
+            {
+                true => Ok(CommandResponse::Yes),
+                false => Ok(CommandResponse::No)
+            }
+        },
+        Command::FocusCurrent => {
+            do_update_stacks(bspc, stacks)?;
+
+            match bspwm::get_focused_node(bspc)?
+            {
+                Some(node) => {
+                    //The outer stacks decide where the inner stacks end up,
+                    //so they are laid out first. The node is focused once all
+                    //of them are laid out
+                    let roots = stack_roots(stacks);
+                    let mut laid_out = false;
+                    for index in stacks_containing_node(bspc, stacks, node)
+                    {
+                        laid_out = stacks[index].layout_node_by_id(bspc, config, animations, &roots, node)? || laid_out;
+                    }
+
+                    if laid_out
+                    {
+                        bspwm::node_focus(bspc, node)?;
+                    }
+                },
+                None => {}
+            }
+
+
+            Ok(CommandResponse::Done)
+        }
+        Command::UpdateStacks => {
+            do_update_stacks(bspc, stacks)
+        }
+        Command::RelayoutDesktops(desktops) => {
+            do_update_stacks(bspc, stacks)?;
+
+            //bspwm focuses new windows, so the focused window is the one that
+            //stays expanded
+            if let Some(focused) = bspwm::get_focused_node(bspc)?
+            {
+                for index in stacks_containing_node(bspc, stacks, focused)
+                {
+                    stacks[index].expanded = Some(focused);
+                }
+            }
+
+            let changed = stacks.iter()
+                .map(|stack| stack.root)
+                .filter(|root| bspwm::get_node_desktop(bspc, *root).map(|desktop| desktops.contains(&desktop)).unwrap_or(false))
+                .collect();
+
+            relayout_stacks(bspc, config, animations, stacks, &changed)?;
+            Ok(CommandResponse::Done)
+        }
+        Command::Move(direction) => {
+            match bspwm::get_focused_node(bspc)?
+            {
+                Some(focused) => move_in_stack(bspc, config, animations, stacks, focused, &direction),
+                None => Ok(CommandResponse::NoStackExists)
+            }
+        }
+        Command::AddToStack(selector, position) => {
+            let node = match bspwm::first_node(bspwm::node_query(bspc, &selector))?
+            {
+                Some(node) => node,
+                None => return Ok(CommandResponse::NoSuchNode)
+            };
+
+            match bspwm::get_focused_node(bspc)?
+            {
+                Some(focused) => add_to_stack(bspc, config, animations, stacks, focused, node, position),
+                None => Ok(CommandResponse::NoStackExists)
+            }
+        }
+        Command::EjectFocused => {
+            match bspwm::get_focused_node(bspc)?
+            {
+                Some(focused) => eject_from_stack(bspc, config, animations, stacks, focused),
+                None => Ok(CommandResponse::NoStackExists)
+            }
+        }
+        Command::ReorderInStack(direction) => {
+            match bspwm::get_focused_node(bspc)?
+            {
+                Some(focused) => reorder_in_stack(bspc, config, animations, stacks, focused, &direction),
+                None => Ok(CommandResponse::NoStackExists)
+            }
+        }
+        Command::MoveFocused(target) => {
+            match bspwm::get_focused_node(bspc)?
+            {
+                Some(focused) => move_focused(bspc, config, animations, stacks, focused, &target, false),
+                None => Ok(CommandResponse::NoSuchNode)
+            }
+        }
+        Command::SwapFocused(target) => {
+            match bspwm::get_focused_node(bspc)?
+            {
+                Some(focused) => move_focused(bspc, config, animations, stacks, focused, &target, true),
+                None => Ok(CommandResponse::NoSuchNode)
+            }
+        }
+        Command::SetStackMode(mode) => {
+            let focused = match bspwm::get_focused_node(bspc)?
+            {
+                Some(focused) => focused,
+                None => return Ok(CommandResponse::NoStackExists)
+            };
+
+            let roots = stack_roots(stacks);
+            match find_innermost_stack(bspc, stacks, focused)
+            {
+                Some(index) => {
+                    stacks[index].set_mode(bspc, config, animations, &roots, mode)?;
+                    Ok(CommandResponse::Done)
+                }
+                None => Ok(CommandResponse::NoStackExists)
+            }
+        }
+        Command::StackContaining(node) => {
+            match find_innermost_stack(bspc, stacks, node)
+            {
+                Some(index) => Ok(CommandResponse::Stack(stacks[index].info(bspc, &stack_roots(stacks))?)),
+                None => Ok(CommandResponse::NoStackExists)
+            }
+        }
+        Command::ListStacks => Ok(CommandResponse::Stacks(stack_infos(bspc, stacks)))
+    }
+}
+
+/**
+    State shared between the command server and the event loop
+ */
+struct Daemon
+{
+    config: Config,
+    stacks: Vec<StackState>,
+    state_path: Option<PathBuf>,
+    /// Clients listening for tab bar updates
+    tab_subscribers: Vec<UnixStream>,
+    /// The last tab bars sent to the subscribers, as json
+    last_tab_bars: Option<String>,
+    /// Whether to print how many bspc calls each command used
+    print_call_counts: bool,
+    /// The stacks as they were after the last command, to find the changes
+    /// that hooks are run for. Only kept up to date when there are hooks
+    stack_infos: Vec<StackInfo>,
+    /// The node that rspwm focused last, or that the last focus event was for.
+    /// bspwm reports the nodes that rspwm focuses as focus events too
+    last_focused: Option<u64>,
+    /// The stack layout changes that are still being animated
+    animations: Animations
+}
+
+impl Daemon
+{
+    fn new(config: Config, stacks: Vec<StackState>, state_path: Option<PathBuf>) -> Daemon
+    {
+        Daemon {
+            config: config,
+            stacks: stacks,
+            state_path: state_path,
+            tab_subscribers: vec!(),
+            last_tab_bars: None,
+            print_call_counts: false,
+            stack_infos: vec!(),
+            last_focused: None,
+            animations: Animations::new()
+        }
+    }
+
+    /**
+        Runs a command, writes the stacks to the state file if they were changed
+        and tells the tab subscribers about any changes.
+
+        The queries of the command are answered from a single snapshot of the
+        bspwm tree
+     */
+    fn run_command(&mut self, bspc: &dyn Backend, command: Command) -> CommandResponse
+    {
+        let previous_stacks = self.stacks.clone();
+        let description = format!("{:?}", command);
+
+        let snapshot = backend::Snapshot::new(bspc);
+
+        let response = handle_command(&snapshot, &self.config, &self.animations, &mut self.stacks, command);
+
+        if let Some(node) = snapshot.focused_node()
+        {
+            self.last_focused = Some(node);
+        }
+
+        if self.stacks != previous_stacks
+        {
+            save_stacks(&self.stacks, self.state_path.as_ref());
+        }
+
+        self.publish_tab_bars(&snapshot);
+        self.run_hooks(&snapshot);
+
+        if self.print_call_counts
+        {
+            println!("{} used {} bspc calls", description, snapshot.calls());
+        }
+
+        response
+    }
+
+    /**
+        Replaces the config and lays out the stacks again with the new settings.
+        Changes to the command server settings are reported but not applied
+     */
+    fn reload_config(&mut self, bspc: &dyn Backend, config: Config)
+    {
+        //The command server is already listening with the old settings
+        if config.tcp_port != self.config.tcp_port || config.timeout_seconds != self.config.timeout_seconds
+        {
+            println!("tcp_port and timeout_seconds only take effect when rspwm is restarted");
+        }
+
+        self.config = config;
+
+        let snapshot = backend::Snapshot::new(bspc);
+        let bspc = &snapshot;
+
+        //Outer stacks are laid out before the stacks nested in them
+        let roots = stack_roots(&self.stacks);
+        for index in outermost_first(bspc, &self.stacks)
+        {
+            if self.stacks[index].expanded.is_none()
+            {
+                continue
+            }
+
+            if let Err(e) = self.stacks[index].relayout(bspc, &self.config, &self.animations, &roots)
+            {
+                println!("{}", e);
+            }
+        }
+
+        self.remember_stacks(bspc);
+    }
+
+    /**
+        Runs the hooks for the changes to the stacks since the last command
+     */
+    fn run_hooks(&mut self, bspc: &dyn Backend)
+    {
+        if self.config.hooks.is_empty()
+        {
+            return
+        }
+
+        //Diffing against a partial list would report the stacks that could
+        //not be queried as removed, and as created again the next time
+        let roots = stack_roots(&self.stacks);
+        let infos = match self.stacks.iter().map(|stack| stack.info(bspc, &roots)).collect::<Result<Vec<_>, _>>()
+        {
+            Ok(infos) => infos,
+            Err(e) => {
+                println!("Not running hooks: {}", e);
+                return
+            }
+        };
+        let changes = hooks::stack_changes(&self.stack_infos, &infos);
+
+        if !changes.is_empty()
+        {
+            let focused = bspwm::get_focused_node(bspc).unwrap_or(None);
+            hooks::run(&self.config, changes, focused);
+        }
+
+        self.stack_infos = infos;
+    }
+
+    /**
+        Takes the current stacks as the starting point for the hooks, so that
+        stacks that already exist are not reported as new
+     */
+    fn remember_stacks(&mut self, bspc: &dyn Backend)
+    {
+        self.stack_infos = match self.config.hooks.is_empty()
+        {
+            true => vec!(),
+            false => stack_infos(bspc, &self.stacks)
+        };
+    }
+
+    /**
+        Sends the tab bars to all subscribers if they changed since last time
+     */
+    fn publish_tab_bars(&mut self, bspc: &dyn Backend)
+    {
+        if self.tab_subscribers.is_empty()
+        {
+            return
+        }
+
+        let line = json::encode(&tab_bars(bspc, &self.stacks)).unwrap();
+
+        if self.last_tab_bars.as_ref() != Some(&line)
+        {
+            tabs::publish_line(&mut self.tab_subscribers, &line);
+            self.last_tab_bars = Some(line);
+        }
+    }
+
+    /**
+        Adds a tab subscriber and sends it the current tab bars
+     */
+    fn add_tab_subscriber(&mut self, bspc: &dyn Backend, subscriber: UnixStream)
+    {
+        let line = json::encode(&tab_bars(bspc, &self.stacks)).unwrap();
+
+        tabs::add_subscriber(&mut self.tab_subscribers, subscriber, &line);
+    }
+}
+
+/**
+    Builds the tab bars for all monitors that have stacks on them
+ */
+fn tab_bars(bspc: &dyn Backend, stacks: &Vec<StackState>) -> Vec<TabBar>
+{
+    let mut bars: Vec<TabBar> = vec!();
+
+    for stack in stacks
+    {
+        let root = match bspwm::get_node_tree(bspc, stack.root)
+        {
+            Ok(root) => root,
+            Err(_) => continue
+        };
+
+        let tabs = bspwm::get_node_descendant_leaf_nodes(&root).iter()
+            .map(|leaf| {
+                Tab {
+                    node: leaf.id,
+                    class_name: leaf.client.as_ref().map(|client| client.class_name.clone()),
+                    instance_name: leaf.client.as_ref().map(|client| client.instance_name.clone()),
+                    focused: stack.expanded == Some(leaf.id)
+                }
+            })
+            .collect();
+
+        let stack_tabs = StackTabs {
+            root: stack.root,
+            tabs: tabs
+        };
+
+        let monitor = bspwm::get_node_monitor_name(bspc, stack.root).unwrap_or(String::new());
+
+        match bars.iter().position(|bar| bar.monitor == monitor)
+        {
+            Some(index) => bars[index].stacks.push(stack_tabs),
+            None => bars.push(TabBar {monitor: monitor, stacks: vec!(stack_tabs)})
+        }
+    }
+
+    bars
+}
+
+/**
+    Writes the stacks to the state file. Nothing is written if there is no
+    state file
+ */
+fn save_stacks(stacks: &Vec<StackState>, state_path: Option<&PathBuf>)
+{
+    if let Some(path) = state_path
+    {
+        if let Err(msg) = state::save(path, stacks)
+        {
+            println!("{}", msg);
+        }
+    }
+}
+
+/**
+    Loads the stacks that were saved by a previous instance of the daemon.
+    Stacks whose root no longer exists or is now a single window are dropped
+ */
+fn restore_stacks(bspc: &dyn Backend, state_path: Option<&PathBuf>) -> Vec<StackState>
+{
+    let saved = match state_path.map(|path| state::load(path))
+    {
+        Some(Ok(Some(stacks))) => stacks,
+        Some(Err(msg)) => {
+            println!("{}", msg);
+            vec!()
+        }
+        _ => vec!()
+    };
+
+    let mut stacks = saved.clone();
+    if let Err(e) = do_update_stacks(bspc, &mut stacks)
+    {
+        println!("Could not check the restored stacks: {}", e);
+    }
+    remember_root_children(bspc, &mut stacks);
+
+    stacks.retain(|stack| {
+        match bspwm::get_node_tree(bspc, stack.root)
+        {
+            Ok(root) => bspwm::get_node_children(&root).is_some(),
+            Err(_) => true
+        }
+    });
+
+    if stacks != saved
+    {
+        save_stacks(&stacks, state_path);
+    }
+
+    println!("Restored {} stacks", stacks.len());
+    stacks
+}
+
+/**
+    Returns the command that should be run in response to a bspwm event
+ */
+fn command_for_event(event: &bspwm::Event) -> Command
+{
+    match *event
+    {
+        bspwm::Event::NodeFocus(_) | bspwm::Event::DesktopFocus => Command::FocusCurrent,
+        bspwm::Event::NodeAdd(desktop)
+            | bspwm::Event::NodeRemove(desktop) => Command::RelayoutDesktops(vec!(desktop)),
+        bspwm::Event::NodeTransfer(source, destination) => Command::RelayoutDesktops(vec!(source, destination))
+    }
+}
+
+/**
+    Subscribes to bspwm events and keeps the stacks up to date until the
+    subscription ends.
+
+    Focusing a node makes bspwm report a node_focus event for it, also when
+    rspwm focused it while running a command. Focus events for the node that
+    was focused last are ignored
+ */
+fn run_event_loop(bspc: &dyn Backend, daemon: &Mutex<Daemon>) -> Result<(), BspwmError>
+{
+    let events = bspc.subscribe(&bspwm::Event::names())?;
+
+    for line in BufRead::lines(events)
+    {
+        let line = line.map_err(|e| BspwmError::Spawn(format!("Failed to read bspwm event: {}", e)))?;
+
+        let event = match bspwm::Event::parse(&line)
+        {
+            Some(event) => event,
+            None => continue
+        };
+
+        let mut daemon = daemon.lock().unwrap();
+
+        if let bspwm::Event::NodeFocus(node) = event
+        {
+            if daemon.last_focused == Some(node)
+            {
+                continue
+            }
+            daemon.last_focused = Some(node);
+        }
+
+        daemon.run_command(bspc, command_for_event(&event));
+    }
+
+    Ok(())
+}
+
+/**
+    The write end of the pipe that SIGHUP is reported on
+ */
+static SIGHUP_PIPE: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn handle_sighup(_: libc::c_int)
+{
+    //Only async signal safe functions may be called here
+    let fd = SIGHUP_PIPE.load(Ordering::SeqCst) as libc::c_int;
+    unsafe
+    {
+        libc::write(fd, [0u8].as_ptr() as *const libc::c_void, 1);
+    }
+}
+
+/**
+    Installs a SIGHUP handler. The returned file can be read one byte at a time,
+    each byte is a received SIGHUP
+ */
+fn sighup_reader() -> Result<File, String>
+{
+    let mut fds = [0 as libc::c_int; 2];
+
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0
+    {
+        return Err("Failed to create a pipe for SIGHUP".to_string())
+    }
+
+    SIGHUP_PIPE.store(fds[1] as usize, Ordering::SeqCst);
+
+    let handler = handle_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGHUP, handler) } == libc::SIG_ERR
+    {
+        return Err("Failed to install a SIGHUP handler".to_string())
+    }
+
+    Ok(unsafe { File::from_raw_fd(fds[0]) })
+}
+
+/**
+    Reloads the config file every time rspwm receives SIGHUP. The stacks are kept,
+    an invalid config file is reported and ignored
+ */
+fn reload_config_on_sighup(config_path: Option<PathBuf>, daemon: Arc<Mutex<Daemon>>)
+{
+    let mut signals = match sighup_reader()
+    {
+        Ok(signals) => signals,
+        Err(msg) => {
+            println!("{}, the config will not be reloaded", msg);
+            return
+        }
+    };
+
+    thread::spawn(move || {
+        let bspc = backend::default_backend();
+        let mut buffer = [0; 1];
+
+        while let Ok(1) = signals.read(&mut buffer)
+        {
+            let config = match config_path
+            {
+                Some(ref path) => Config::load(path),
+                None => Ok(Config::default())
+            };
+
+            match config
+            {
+                Ok(config) => {
+                    daemon.lock().unwrap().reload_config(&*bspc, config);
+                    println!("Reloaded config");
+                }
+                Err(msg) => println!("{}\nKeeping the previous config", msg)
+            }
+        }
+    });
+}
+
+fn main() 
+{
+    let matches = App::new("rspwm")
+        .about("Daemon keeping track of bspwm stacks")
+        .arg(Arg::with_name("tcp")
+            .long("tcp")
+            .help("Listen on TCP instead of a UNIX socket. The port is 9232 unless configured"))
+        .arg(Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .help("Config file to use instead of $XDG_CONFIG_HOME/rspwm/config.toml"))
+        .arg(Arg::with_name("check-config")
+            .long("check-config")
+            .help("Reports unknown or invalid keys in the config file and exits"))
+        .arg(Arg::with_name("stats")
+            .long("stats")
+            .help("Prints how many bspc calls each command used"))
+        .get_matches();
+
+    let config_path = matches.value_of("config")
+        .map(PathBuf::from)
+        .or(config::config_file_path());
+
+    let config = match config_path.as_ref().map(|path| Config::load(path))
+    {
+        Some(Ok(config)) => config,
+        Some(Err(msg)) => {
+            println!("{}", msg);
+            match matches.is_present("check-config")
+            {
+                true => process::exit(1),
+                false => Config::default()
+            }
+        }
+        None => Config::default()
+    };
+
+    if matches.is_present("check-config")
+    {
+        match config_path
+        {
+            Some(ref path) if path.exists() => println!("{} is valid", path.display()),
+            Some(path) => println!("{} does not exist, using the defaults", path.display()),
+            None => println!("No config file found, using the defaults")
+        }
+        return
+    }
+
+    let transport = match Transport::select(matches.is_present("tcp"), config.tcp_port)
+    {
+        Ok(transport) => transport,
+        Err(msg) => {
+            println!("{}", msg);
+            return
+        }
+    };
+
+    let bspc = backend::default_backend();
+
+    let state_path = state::state_file_path();
+    if state_path.is_none()
+    {
+        println!("No state directory found, stacks will not be persisted");
+    }
+
+    let stacks = restore_stacks(&*bspc, state_path.as_ref());
+    let timeout = Some(Duration::new(config.timeout_seconds, 0));
+    let mut daemon = Daemon::new(config, stacks, state_path);
+    daemon.print_call_counts = matches.is_present("stats");
+    daemon.remember_stacks(&*bspc);
+    let daemon = Arc::new(Mutex::new(daemon));
+
+    reload_config_on_sighup(config_path, daemon.clone());
+
+    println!("Running rspwm server. Control using rspc");
+
+    {
+        let daemon = daemon.clone();
+        thread::spawn(move || {
+            let bspc = backend::default_backend();
+
+            //Resubscribe if bspwm is restarted
+            loop
+            {
+                match run_event_loop(&*bspc, &daemon)
+                {
+                    Ok(()) => println!("bspwm subscription ended"),
+                    Err(msg) => println!("{}", msg)
+                }
+
+                thread::sleep(Duration::new(EVENT_RETRY_SECONDS, 0));
+            }
+        });
+    }
+
+    match tabs::feed_socket_path().map(|path| transport::bind_private_socket(&path))
+    {
+        Some(Ok(listener)) => {
+            let daemon = daemon.clone();
+            thread::spawn(move || {
+                let bspc = backend::default_backend();
+
+                for subscriber in listener.incoming().filter_map(|stream| stream.ok())
+                {
+                    daemon.lock().unwrap().add_tab_subscriber(&*bspc, subscriber);
+                }
+            });
+        }
+        Some(Err(msg)) => println!("Not publishing tab bars: {}", msg),
+        None => println!("XDG_RUNTIME_DIR is not set, not publishing tab bars")
+    }
+
+    let command_handler = |command: Command|
+    {
+        daemon.lock().unwrap().run_command(&*bspc, command)
+    };
+
+    transport.serve(command_handler, timeout).unwrap();
+}
+
+
+
+
+
+
+
+
+
+#[cfg(test)]
+mod stack_tests
+{
+    use super::*;
+
+    use backend::MockBackend;
+
+    fn stack(root: u64) -> StackState
+    {
+        StackState{root: root, expanded: None, mode: StackMode::Collapsed, children: None}
+    }
+
+    /**
+        Runs a command with the default config and checks the debug output
+        of the response
+     */
+    fn assert_command(bspc: &dyn Backend, stacks: &mut Vec<StackState>, command: Command, expected: &str)
+    {
+        let response = handle_command(bspc, &Config::default(), &Animations::new(), stacks, command);
+        assert_eq!(format!("{:?}", response), expected);
+    }
+
+    fn sample_json() -> json::Json
+    {
+        let mut sample = String::new();
+        File::open("sample_tree.json").unwrap().read_to_string(&mut sample).unwrap();
+        json::Json::from_str(&sample).unwrap()
+    }
+
+    /**
+        Removes a leaf from a tree. Its sibling takes the place of the parent,
+        like in bspwm
+     */
+    fn remove_leaf(node: &mut json::Json, leaf: u64)
+    {
+        let id = |node: &json::Json, child: &str| node.find(child).and_then(|child| child.find("id")).and_then(|id| id.as_u64());
+
+        let sibling = match (id(node, "firstChild"), id(node, "secondChild"))
+        {
+            (Some(first), _) if first == leaf => node.find("secondChild").cloned(),
+            (_, Some(second)) if second == leaf => node.find("firstChild").cloned(),
+            _ => None
+        };
+
+        match sibling
+        {
+            Some(sibling) => *node = sibling,
+            None => {
+                for child in node.as_object_mut().unwrap().values_mut().filter(|child| child.find("id").is_some())
+                {
+                    remove_leaf(child, leaf);
+                }
+            }
+        }
+    }
+
+    fn hide(node: &mut json::Json, id: u64)
+    {
+        let object = node.as_object_mut().unwrap();
+
+        if object.get("id").and_then(|id| id.as_u64()) == Some(id)
+        {
+            object.insert("hidden".to_string(), json::Json::Boolean(true));
+        }
+        for child in object.values_mut().filter(|child| child.find("id").is_some())
+        {
+            hide(child, id);
+        }
+    }
+
+    #[test]
+    fn focus_in_stack_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stack = stack(4194628);
+
+        stack.focus_node_by_id(&bspc, &Config::default(), &Animations::new(), &vec!(), 29526298).unwrap();
+
+        assert_eq!(bspc.commands(), vec!(
+                "node 0x400144 -r 0.026561044",
+                "node 0x40014C -r 0.94688994",
+                "node 0x400150 -r 0.5089286",
+                "node -f 29526298",
+            ));
+
+        //Nodes outside the stack are left alone
+        bspc.clear_commands();
+        stack.focus_node_by_id(&bspc, &Config::default(), &Animations::new(), &vec!(), 29475921).unwrap();
+        assert!(bspc.commands().is_empty());
+    }
+
+    #[test]
+    fn snapshot_command_test()
+    {
+        let bspc = MockBackend::from_sample();
+        bspc.set_focused(Some(29526298));
+
+        //Answering the queries from a snapshot gives the same layout
+        let mut daemon = Daemon::new(Config::default(), vec!(stack(4194628)), None);
+        daemon.run_command(&bspc, Command::FocusCurrent);
+
+        assert_eq!(bspc.commands(), vec!(
+                "node 0x400144 -r 0.026561044",
+                "node 0x40014C -r 0.94688994",
+                "node 0x400150 -r 0.5089286",
+                "node -f 29526298",
+            ));
+    }
+
+    #[test]
+    fn focus_in_mixed_stack_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stack = stack(4194628);
+
+        //0x40014E is split vertically inside the horizontal stack, it is expanded
+        //as a whole and keeps its own ratio
+        stack.focus_node_by_id(&bspc, &Config::default(), &Animations::new(), &vec!(), 29541339).unwrap();
+
+        assert_eq!(bspc.commands(), vec!(
+                "node 0x400144 -r 0.026561044",
+                "node 0x40014C -r 0.027272727",
+                "node 0x400150 -r 0.9729597",
+                "node -f 29541339",
+            ));
+        assert_eq!(stack.expanded, Some(29541339));
+
+        //Moving along the stack leaves the member instead of moving inside it
+        let mut stacks = vec!(stack);
+        let config = Config::default();
+        let south = bspwm::CardinalDirection::South;
+        let north = bspwm::CardinalDirection::North;
+
+        bspc.clear_commands();
+        move_in_stack(&bspc, &config, &Animations::new(), &mut stacks, 29541313, &south).unwrap();
+        assert_eq!(bspc.commands().last().unwrap(), "node -f 29541363");
+
+        bspc.clear_commands();
+        move_in_stack(&bspc, &config, &Animations::new(), &mut stacks, 29541363, &north).unwrap();
+        assert_eq!(bspc.commands().last().unwrap(), "node -f 29541313");
+    }
+
+    #[test]
+    fn hidden_stack_test()
+    {
+        use std::io::prelude::*;
+
+        let bspc = MockBackend::from_sample();
+        let mut stacks = vec!(StackState{root: 4194628, expanded: None, mode: StackMode::Hidden, children: None});
+
+        //The members that are not expanded are hidden instead of collapsed
+        bspc.set_focused(Some(29526298));
+        handle_command(&bspc, &Config::default(), &Animations::new(), &mut stacks, Command::FocusCurrent);
+        assert_eq!(bspc.commands(), vec!(
+                "node 0x1C2B7E3 -g hidden=on",
+                "node 0x40014E -g hidden=on",
+                "node 0x1C2C3F3 -g hidden=on",
+                "node -f 29526298",
+            ));
+
+        //Removing the stack shows them again
+        let mut sample = String::new();
+        File::open("sample_tree.json").unwrap().read_to_string(&mut sample).unwrap();
+        let position = sample.find("\"id\":29538275").unwrap();
+        let hidden_tree = sample[..position].to_string()
+            + &sample[position..].replacen("\"hidden\":false", "\"hidden\":true", 1);
+        bspc.set_tree(&hidden_tree);
+
+        bspc.clear_commands();
+        remove_stack_containing_node(&bspc, &Animations::new(), &mut stacks, 29526298).unwrap();
+        assert_eq!(bspc.commands()[0], "node 0x1C2B7E3 -g hidden=off");
+        assert!(stacks.is_empty());
+    }
+
+    #[test]
+    fn create_stack_config_test()
+    {
+        let bspc = MockBackend::from_sample();
+        bspc.set_focused(Some(4194640));
+
+        //The mock puts every node on desktop I
+        let config = Config::parse("[desktops.I]\nfocus_first_leaf_on_create = false").unwrap();
+
+        let mut stacks = vec!();
+        handle_command(&bspc, &config, &Animations::new(), &mut stacks, Command::CreateStack(None));
+
+        assert_eq!(stacks, vec!(StackState{children: Some((4194638, 29541363)), ..stack(4194640)}));
+        assert!(bspc.commands().is_empty());
+
+        //The mode given on creation replaces the configured one
+        bspc.set_focused(Some(4194628));
+        handle_command(&bspc, &config, &Animations::new(), &mut stacks, Command::CreateStack(Some(StackMode::Equal)));
+        assert_eq!(stacks[1].mode, StackMode::Equal);
+    }
+
+    #[test]
+    fn focus_member_by_index_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stack = stack(4194640);
+
+        //0x40014E is split the other way, so it is a single member
+        assert_eq!(stack.focus_member_by_index(&bspc, &Config::default(), &Animations::new(), &vec!(), 1), Ok(Some(29541363)));
+        assert_eq!(stack.focus_member_by_index(&bspc, &Config::default(), &Animations::new(), &vec!(), 2), Ok(None));
+        assert_eq!(stack.expanded, Some(29541363));
+    }
+
+    #[test]
+    fn move_in_stack_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stacks = vec!(stack(4194636));
+
+        bspc.set_focused(Some(29526298));
+
+        //Moving along the stack expands the next leaf
+        assert_command(&bspc, &mut stacks, Command::Move(bspwm::CardinalDirection::South), "Done");
+        assert_eq!(stacks[0].expanded, Some(29541313));
+        assert_eq!(bspc.commands().last(), Some(&"node -f 29541313".to_string()));
+
+        bspc.clear_commands();
+        assert_command(&bspc, &mut stacks, Command::Move(bspwm::CardinalDirection::North), "EndOfStack");
+        assert!(bspc.commands().is_empty());
+
+        assert_command(&bspc, &mut stacks, Command::Move(bspwm::CardinalDirection::East), "UseBspwmFocus");
+
+        bspc.set_focused(Some(29475921));
+        assert_command(&bspc, &mut stacks, Command::Move(bspwm::CardinalDirection::South), "NoStackExists");
+    }
+
+    #[test]
+    fn add_to_stack_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stacks = vec!(stack(4194636));
+        stacks[0].expanded = Some(29526298);
+
+        bspc.set_focused(Some(29526298));
+
+        assert_command(&bspc, &mut stacks, Command::AddToStack("29475921".to_string(), Some(1)), "Done");
+
+        //The second member is 0x40014E as a whole
+        let commands = bspc.commands();
+        assert_eq!(commands[0], "node 0x40014E -p north");
+        assert_eq!(commands[1], "node 0x1C1C451 -n 0x40014E");
+        //The expanded leaf stays expanded
+        assert_eq!(commands[2], "node 0x40014C -r 0.89664805");
+        assert_eq!(stacks[0].root, 4194636);
+
+        assert_command(&bspc, &mut stacks, Command::AddToStack("1234".to_string(), None), "NoSuchNode");
+    }
+
+    #[test]
+    fn eject_from_stack_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stacks = vec!(stack(4194636));
+
+        //Nodes deeper in the stack are moved next to the root
+        bspc.set_focused(Some(29541363));
+        assert_command(&bspc, &mut stacks, Command::EjectFocused, "Done");
+        let commands = bspc.commands();
+        assert_eq!(commands[0], "node 0x40014C -p south");
+        assert_eq!(commands[1], "node 0x1C2C3F3 -n 0x40014C");
+        assert_eq!(commands.last(), Some(&"node -f 29541363".to_string()));
+
+        //Ejecting a child of the root makes its sibling the new root
+        bspc.clear_commands();
+        bspc.set_focused(Some(29526298));
+        handle_command(&bspc, &Config::default(), &Animations::new(), &mut stacks, Command::EjectFocused);
+        assert_eq!(bspc.commands()[0], "node 0x40014C -r 0.5");
+        assert_eq!(stacks[0].root, 4194640);
+    }
+
+    #[test]
+    fn eject_from_hidden_stack_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut tree = sample_json();
+        hide(&mut tree, 29541339);
+        bspc.set_tree(&tree.to_string());
+
+        let mut stacks = vec!(StackState{mode: StackMode::Hidden, expanded: Some(29541313), ..stack(4194638)});
+
+        //Ejecting the shown window of two leaves the other one alone, so it
+        //is shown again as the stack is dropped
+        bspc.set_focused(Some(29541313));
+        assert_command(&bspc, &mut stacks, Command::EjectFocused, "Done");
+
+        assert_eq!(bspc.commands(), vec!(
+                "node 0x40014E -r 0.5",
+                "node 0x1C2C3DB -g hidden=off",
+                "node -f 29541313",
+            ));
+        assert!(stacks.is_empty());
+    }
+
+    #[test]
+    fn removed_root_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stacks = vec!(stack(4194636), StackState{mode: StackMode::Hidden, ..stack(4194638)});
+
+        //The children of the roots are recorded by every command
+        handle_command(&bspc, &Config::default(), &Animations::new(), &mut stacks, Command::UpdateStacks);
+
+        //Closing the first window of 0x40014C puts 0x400150 in its place,
+        //closing a window of 0x40014E leaves a single hidden window
+        let mut tree = sample_json();
+        remove_leaf(&mut tree, 29526298);
+        remove_leaf(&mut tree, 29541313);
+        hide(&mut tree, 29541339);
+        bspc.set_tree(&tree.to_string());
+
+        handle_command(&bspc, &Config::default(), &Animations::new(), &mut stacks, Command::UpdateStacks);
+
+        assert_eq!(stack_roots(&stacks), vec!(4194640));
+        assert_eq!(bspc.commands(), vec!("node 0x1C2C3DB -g hidden=off"));
+    }
+
+    #[test]
+    fn reorder_in_stack_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stacks = vec!(stack(4194628));
+
+        //0x40014E is a single member, so the whole of it swaps places
+        assert_eq!(format!("{:?}", reorder_in_stack(&bspc, &Config::default(), &Animations::new(), &mut stacks, 29541313, &bspwm::CardinalDirection::North)), "Ok(Done)");
+
+        let commands = bspc.commands();
+        assert_eq!(commands[0], "node 0x40014E -s 0x1C2891A");
+        assert_eq!(commands.last().unwrap(), "node -f 29541313");
+        assert_eq!(stacks[0].expanded, Some(29541313));
+
+        assert_eq!(format!("{:?}", reorder_in_stack(&bspc, &Config::default(), &Animations::new(), &mut stacks, 29538275, &bspwm::CardinalDirection::North)), "Ok(EndOfStack)");
+        assert_eq!(format!("{:?}", reorder_in_stack(&bspc, &Config::default(), &Animations::new(), &mut stacks, 29538275, &bspwm::CardinalDirection::West)), "Ok(UseBspwmFocus)");
+    }
+
+    #[test]
+    fn move_focused_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stacks = vec!(stack(4194628));
+
+        //Windows moved into a stack are placed along it
+        let target = bspwm::FocusTarget::Node(29526298);
+        move_focused(&bspc, &Config::default(), &Animations::new(), &mut stacks, 29475921, &target, false).unwrap();
+        assert_eq!(bspc.commands()[0..2].to_vec(), vec!(
+                "node 0x1C2891A -p south",
+                "node 0x1C1C451 -n 0x1C2891A",
+            ));
+
+        //Sending a direct child of the root away makes its sibling the new root
+        bspc.clear_commands();
+        let target = bspwm::FocusTarget::Desktop(5);
+        move_focused(&bspc, &Config::default(), &Animations::new(), &mut stacks, 29538275, &target, false).unwrap();
+        assert_eq!(bspc.commands()[0], "node 0x1C2B7E3 -d 0x5 --follow");
+        assert_eq!(stacks[0].root, 4194636);
+    }
+
+    #[test]
+    fn tab_bars_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stacks = vec!(stack(4194640), stack(4194628));
+        stacks[0].expanded = Some(29541339);
+
+        let bars = tab_bars(&bspc, &stacks);
+
+        assert_eq!(bars.len(), 1);
+        assert_eq!(bars[0].monitor, "DP-1");
+        assert_eq!(bars[0].stacks.iter().map(|stack| stack.root).collect::<Vec<_>>(), vec!(4194640, 4194628));
+
+        let tabs = &bars[0].stacks[0].tabs;
+        assert_eq!(tabs.iter().map(|tab| tab.node).collect::<Vec<_>>(), vec!(29541313, 29541339, 29541363));
+        assert_eq!(tabs.iter().map(|tab| tab.focused).collect::<Vec<_>>(), vec!(false, true, false));
+        assert_eq!(tabs[0].class_name, Some("Xfce4-terminal".to_string()));
+    }
+
+    #[test]
+    fn list_stacks_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stacks = vec!(stack(4194636), stack(1234));
+        stacks[0].expanded = Some(29526298);
+
+        //Stacks that can't be queried are left out
+        let expected = CommandResponse::Stacks(vec!(StackInfo {
+                root: 4194636,
+                desktop: Some(1),
+                desktop_name: Some("I".to_string()),
+                monitor: Some(2),
+                monitor_name: Some("DP-1".to_string()),
+                split: bspwm::SplitDirection::Horizontal,
+                leaves: vec!(29526298, 29541313, 29541339, 29541363),
+                members: vec!(vec!(29526298), vec!(29541313, 29541339), vec!(29541363)),
+                expanded: Some(29526298)
+            }));
+        assert_command(&bspc, &mut stacks, Command::ListStacks, &format!("{:?}", expected));
+    }
+
+    #[test]
+    fn remove_nested_stack_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stacks = vec!(stack(4194621), stack(4194636));
+
+        //The innermost stack containing the node is removed first
+        remove_stack_containing_node(&bspc, &Animations::new(), &mut stacks, 29541313).unwrap();
+        assert_eq!(stacks.len(), 1);
+        assert_eq!(stacks[0].root, 4194621);
+        //Only the members of the removed stack are balanced, 0x40014E keeps its ratio
+        assert_eq!(bspc.commands(), vec!("node 0x400150 -r 0.5", "node 0x40014C -r 0.33333334"));
+
+        remove_stack_containing_node(&bspc, &Animations::new(), &mut stacks, 29541313).unwrap();
+        assert!(stacks.is_empty());
+
+        assert_eq!(format!("{:?}", remove_stack_containing_node(&bspc, &Animations::new(), &mut stacks, 29541313)), "Ok(NoStackExists)");
+    }
+
+    #[test]
+    fn nested_stacks_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stacks = vec!(stack(4194636), stack(4194628));
+
+        assert_eq!(stacks_containing_node(&bspc, &stacks, 29541363), vec!(1, 0));
+        assert_eq!(stacks_containing_node(&bspc, &stacks, 29538275), vec!(1));
+
+        //The outer stack is laid out first and treats the inner stack as one member
+        bspc.set_focused(Some(29541363));
+        handle_command(&bspc, &Config::default(), &Animations::new(), &mut stacks, Command::FocusCurrent);
+        assert_eq!(bspc.commands(), vec!(
+                "node 0x400144 -r 0.026561044",
+                "node 0x40014C -r 0.053072624",
+                "node 0x400150 -r 0.05599214",
+                "node -f 29541363",
+            ));
+
+        //irspc asks for the innermost stack
+        let info = stacks[0].info(&bspc, &stack_roots(&stacks)).unwrap();
+        assert_command(&bspc, &mut stacks, Command::StackContaining(29541363), &format!("{:?}", CommandResponse::Stack(info)));
+
+        //A node can only be the root of one stack
+        bspc.set_focused(Some(4194636));
+        assert_command(&bspc, &mut stacks, Command::CreateStack(None), "StackExists");
+        assert_eq!(stacks.len(), 2);
+    }
+
+    #[test]
+    fn animated_nested_stacks_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let snapshot = backend::Snapshot::new(&bspc);
+        let config = Config {animation_duration_ms: animation::FRAME_MS, ..Config::default()};
+        let animations = Animations::new();
+        let mut stacks = vec!(stack(4194636), stack(4194628));
+
+        bspc.set_focused(Some(29541363));
+        handle_command(&snapshot, &config, &animations, &mut stacks, Command::FocusCurrent);
+
+        //The outer stack is changed right away so that the inner one is laid
+        //out from the trees bspwm reports afterwards. Only the inner one is
+        //animated
+        assert_eq!(bspc.commands()[0], "node 0x400144 -r 0.026561044");
+        assert!(!animations.is_running(4194628));
+
+        while animations.is_running(4194636)
+        {
+            thread::sleep(Duration::from_millis(animation::FRAME_MS));
+        }
+
+        let mut ratios = bspc.commands().into_iter()
+            .filter(|command| command.contains(" -r "))
+            .collect::<Vec<_>>();
+        ratios.sort();
+        assert_eq!(ratios, vec!(
+                "node 0x400144 -r 0.026561044",
+                "node 0x40014C -r 0.053072624",
+                "node 0x400150 -r 0.05599214",
+            ));
+    }
+
+    #[test]
+    fn snapshot_focus_current_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let snapshot = backend::Snapshot::new(&bspc);
+        let mut stacks = vec!(stack(4194636), StackState{mode: StackMode::Hidden, ..stack(4194638)});
+
+        bspc.set_focused(Some(29541339));
+        assert_command(&snapshot, &mut stacks, Command::FocusCurrent, "Done");
+
+        //Focusing updates the snapshot and the window gap is only read once,
+        //but ratio and hidden flag changes make the next query for a tree
+        //fetch the trees again
+        assert_eq!(bspc.commands(), vec!(
+                "node 0x40014C -r 0.053072624",
+                "node 0x400150 -r 0.9459725",
+                "node 0x1C2C3C1 -g hidden=on",
+                "node -f 29541339",
+            ));
+        assert_eq!(snapshot.calls(), bspc.commands().len() + 8);
+    }
+
+    #[test]
+    fn update_stacks_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stacks = vec!(stack(4194628), stack(1234));
+
+        assert!(is_node_in_stacks(&bspc, &stacks, 29541339));
+        assert!(!is_node_in_stacks(&bspc, &stacks, 29475921));
+
+        do_update_stacks(&bspc, &mut stacks).unwrap();
+
+        assert_eq!(stacks.len(), 1);
+        assert_eq!(stacks[0].root, 4194628);
+    }
+
+    #[test]
+    fn bspwm_failure_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut stacks = vec!(stack(4194640));
+        bspc.set_unavailable(true);
+
+        //Failures are reported instead of crashing
+        assert_command(&bspc, &mut stacks, Command::FocusCurrent, "Failed(Spawn(\"bspwm is unavailable\"))");
+
+        //and stacks are not dropped because bspwm could not be asked about them
+        handle_command(&bspc, &Config::default(), &Animations::new(), &mut stacks, Command::UpdateStacks);
+        assert_eq!(stacks, vec!(stack(4194640)));
+
+        //Removed nodes are reported as missing
+        bspc.set_unavailable(false);
+        assert_eq!(stacks[0].focus_member_by_index(&bspc, &Config::default(), &Animations::new(), &vec!(), 0), Ok(Some(29541313)));
+        assert_eq!(stack(1234).focus_member_by_index(&bspc, &Config::default(), &Animations::new(), &vec!(), 0), Err(BspwmError::MissingNode(1234)));
+    }
+
+    #[test]
+    fn event_loop_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let daemon = Mutex::new(Daemon::new(Config::default(), vec!(stack(4194640)), None));
+
+        //The second focus event is the one caused by the stack itself
+        bspc.set_focused(Some(29541363));
+        bspc.set_events(concat!(
+                "node_focus 0x00200002 0x00200003 0x01C2C3F3\n",
+                "node_focus 0x00200002 0x00200003 0x01C2C3F3\n",
+                "node_geometry 0x00200002 0x00200003 0x01C2C3F3 1x1+0+0\n",
+            ));
+
+        run_event_loop(&bspc, &daemon).unwrap();
+
+        assert_eq!(bspc.commands(), vec!(
+                "node 0x400150 -r 0.10594796",
+                "node -f 29541363",
+            ));
+    }
+
+    #[test]
+    fn failed_hook_query_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let mut config = Config::default();
+        config.hooks.insert("stack_removed".to_string(), vec!("true".to_string()));
+        let mut daemon = Daemon::new(config, vec!(stack(4194640)), None);
+        daemon.remember_stacks(&bspc);
+
+        //A stack that can't be queried is not taken as removed
+        bspc.set_unavailable(true);
+        daemon.run_hooks(&bspc);
+        assert_eq!(daemon.stack_infos.iter().map(|info| info.root).collect::<Vec<_>>(), vec!(4194640));
+
+        bspc.set_unavailable(false);
+        daemon.run_hooks(&bspc);
+        assert_eq!(daemon.stack_infos.iter().map(|info| info.root).collect::<Vec<_>>(), vec!(4194640));
+    }
+
+    #[test]
+    fn restore_stacks_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let path = ::std::env::temp_dir()
+            .join(format!("rspwm-restore-test-{}", process::id()))
+            .join("stacks.json");
+
+        //Stacks whose root is gone or has become a leaf are dropped
+        save_stacks(&vec!(stack(4194640), stack(29541363), stack(1234)), Some(&path));
+        let restored = vec!(StackState{children: Some((4194638, 29541363)), ..stack(4194640)});
+        assert_eq!(restore_stacks(&bspc, Some(&path)), restored);
+        assert_eq!(state::load::<Vec<StackState>>(&path), Ok(Some(restored)));
+
+        ::std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn command_focus_event_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let daemon = Mutex::new(Daemon::new(Config::default(), vec!(stack(4194640)), None));
+        bspc.set_focused(Some(29541363));
+
+        //The focus event for a node that a command focused is not handled again
+        daemon.lock().unwrap().run_command(&bspc, Command::FocusCurrent);
+        bspc.clear_commands();
+        bspc.set_events("node_focus 0x00200002 0x00200003 0x01C2C3F3\n");
+
+        run_event_loop(&bspc, &daemon).unwrap();
+        assert_eq!(bspc.commands(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn node_add_event_test()
+    {
+        let bspc = MockBackend::from_sample();
+        let daemon = Mutex::new(Daemon::new(Config::default(), vec!(stack(4194640)), None));
+
+        //Only the stacks on the desktop are laid out again, around the focused window
+        bspc.set_focused(Some(29541363));
+        bspc.set_events(concat!(
+                "node_add 0x00000002 0x00000005 0x00400150 0x01C2C3F3\n",
+                "node_add 0x00000002 0x00000001 0x00400150 0x01C2C3F3\n",
+            ));
+
+        run_event_loop(&bspc, &daemon).unwrap();
+
+        assert_eq!(bspc.commands(), vec!("node 0x400150 -r 0.10594796"));
+        assert_eq!(daemon.lock().unwrap().stacks[0].expanded, Some(29541363));
+    }
+}
@@ -1,13 +1,34 @@
+use bspwm::{SplitDirection, CardinalDirection, FocusTarget, BspwmError};
+use config::StackMode;
 
-#[derive(Debug, RustcEncodable, RustcDecodable)]
+#[derive(Debug, PartialEq, RustcEncodable, RustcDecodable)]
 pub enum Command
 {
-    CreateStack,
+    /// Creates a stack on the focused node, in the mode or the configured one
+    CreateStack(Option<StackMode>),
     RemoveFocused,
     IsFocusedInStack,
-    //Move(bspwm::CardinalDirection),
+    Move(CardinalDirection),
+    /// Moves the node matching the selector into the focused stack, before
+    /// the member at the index, or after the last member if there is no index
+    AddToStack(String, Option<usize>),
+    EjectFocused,
     FocusCurrent,
     UpdateStacks,
+    /// Lays out the stacks on the desktops again after windows were added
+    /// to them or removed from them
+    RelayoutDesktops(Vec<u64>),
+    ListStacks,
+    /// Describes the innermost stack containing the node
+    StackContaining(u64),
+    /// Swaps the focused stack member with the next one in the direction
+    ReorderInStack(CardinalDirection),
+    /// Moves the focused node next to the target node or to the target desktop
+    MoveFocused(FocusTarget),
+    /// Swaps the focused node with the target node or sends it to the target desktop
+    SwapFocused(FocusTarget),
+    /// Changes the mode of the innermost stack containing the focused node
+    SetStackMode(StackMode),
 }
 #[derive(Debug, RustcEncodable, RustcDecodable)]
 pub enum CommandResponse
@@ -16,6 +37,36 @@ pub enum CommandResponse
     NoStackExists,
     EndOfStack,
     Yes,
-    No
+    No,
+    Stacks(Vec<StackInfo>),
+    Stack(StackInfo),
+    /// The direction is not along the stack, use the normal bspwm focus instead
+    UseBspwmFocus,
+    NoSuchNode,
+    /// The focused node is already the root of a stack
+    StackExists,
+    /// bspwm failed while running the command
+    Failed(BspwmError)
+}
+
+/**
+    Description of a stack that the daemon is tracking
+*/
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct StackInfo
+{
+    pub root: u64,
+    pub desktop: Option<u64>,
+    pub desktop_name: Option<String>,
+    pub monitor: Option<u64>,
+    pub monitor_name: Option<String>,
+    pub split: SplitDirection,
+    /// The leaves of the stack in order
+    pub leaves: Vec<u64>,
+    /// The leaves of each member of the stack in order. Subtrees that are
+    /// split the other way and nested stacks are single members
+    pub members: Vec<Vec<u64>>,
+    /// The leaf that is currently expanded, if any
+    pub expanded: Option<u64>
 }
 
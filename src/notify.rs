@@ -0,0 +1,208 @@
+
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use notify_rust::Notification;
+
+use config::{Config, NotificationBackend};
+
+/**
+  The id of the last desktop notification so that the next one can replace
+  it, 0 if none has been shown
+*/
+static LAST_DESKTOP_NOTIFICATION: AtomicUsize = AtomicUsize::new(0);
+
+/**
+  Something that happened in the daemon that the user may want to know about
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event
+{
+    StackCreated,
+    StackRemoved,
+    AlreadyAStack,
+    NoFocusedWindow
+}
+
+impl Event
+{
+    /**
+      The name of the event in the `[notifications]` config table
+    */
+    pub fn name(&self) -> &'static str
+    {
+        match *self
+        {
+            Event::StackCreated => "stack_created",
+            Event::StackRemoved => "stack_removed",
+            Event::AlreadyAStack => "already_a_stack",
+            Event::NoFocusedWindow => "no_focused_window"
+        }
+    }
+
+    pub fn message(&self) -> &'static str
+    {
+        match *self
+        {
+            Event::StackCreated => "Stack created",
+            Event::StackRemoved => "Stack removed",
+            Event::AlreadyAStack => "Already a stack",
+            Event::NoFocusedWindow => "No focused window"
+        }
+    }
+}
+
+/**
+  A way of telling the user about events
+*/
+pub trait Notifier
+{
+    fn notify(&self, event: Event) -> Result<(), String>;
+}
+
+/**
+  Shows desktop notifications through the notification daemon. Every
+  notification replaces the previous one
+*/
+pub struct DesktopNotifier
+{
+    pub timeout_ms: u64
+}
+
+/**
+  Prints the events on stderr
+*/
+pub struct LogNotifier;
+
+pub struct SilentNotifier;
+
+/**
+  Runs a program with the event name and message as the last arguments,
+  without waiting for it
+*/
+pub struct CommandNotifier
+{
+    pub command: Vec<String>
+}
+
+impl Notifier for DesktopNotifier
+{
+    fn notify(&self, event: Event) -> Result<(), String>
+    {
+        let mut notification = Notification::new();
+        notification.summary(event.message())
+            .body("")
+            .timeout(self.timeout_ms as i32);
+
+        match LAST_DESKTOP_NOTIFICATION.load(Ordering::SeqCst)
+        {
+            0 => {}
+            last => {
+                notification.id(last as u32);
+            }
+        }
+
+        let handle = notification.show()
+            .map_err(|e| format!("Failed to show notification: {:?}", e))?;
+
+        LAST_DESKTOP_NOTIFICATION.store(handle.id() as usize, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Notifier for LogNotifier
+{
+    fn notify(&self, event: Event) -> Result<(), String>
+    {
+        eprintln!("{}", event.message());
+        Ok(())
+    }
+}
+
+impl Notifier for SilentNotifier
+{
+    fn notify(&self, _event: Event) -> Result<(), String>
+    {
+        Ok(())
+    }
+}
+
+impl CommandNotifier
+{
+    /**
+      The program and its arguments for the event
+    */
+    fn arguments(&self, event: Event) -> Vec<String>
+    {
+        let mut arguments = self.command.clone();
+        arguments.push(event.name().to_string());
+        arguments.push(event.message().to_string());
+        arguments
+    }
+}
+
+impl Notifier for CommandNotifier
+{
+    fn notify(&self, event: Event) -> Result<(), String>
+    {
+        let arguments = self.arguments(event);
+
+        let mut child = process::Command::new(&arguments[0])
+            .args(&arguments[1..])
+            .spawn()
+            .map_err(|e| format!("Failed to run {}: {}", arguments[0], e))?;
+
+        //Reap the program in the background so that a slow one doesn't hold up the daemon
+        thread::spawn(move || child.wait());
+        Ok(())
+    }
+}
+
+/**
+  Returns the notifier for a backend
+*/
+pub fn for_backend(backend: &NotificationBackend, config: &Config) -> Box<dyn Notifier>
+{
+    match *backend
+    {
+        NotificationBackend::Desktop => Box::new(DesktopNotifier{timeout_ms: config.notification_timeout_ms}),
+        NotificationBackend::Log => Box::new(LogNotifier),
+        NotificationBackend::None => Box::new(SilentNotifier),
+        NotificationBackend::Command(ref command) => Box::new(CommandNotifier{command: command.clone()})
+    }
+}
+
+/**
+  Tells the user about the event with the backend configured for it.
+  Failures are logged
+*/
+pub fn notify(config: &Config, event: Event)
+{
+    let notifier = for_backend(&config.notification_backend(event.name()), config);
+
+    if let Err(msg) = notifier.notify(event)
+    {
+        println!("{}", msg);
+    }
+}
+
+
+#[cfg(test)]
+mod notify_tests
+{
+    use super::*;
+
+    #[test]
+    fn command_notifier_test()
+    {
+        let notifier = CommandNotifier{command: vec!("notify-send".to_string(), "-u".to_string())};
+
+        assert_eq!(notifier.arguments(Event::NoFocusedWindow), vec!(
+                "notify-send", "-u", "no_focused_window", "No focused window"
+            ));
+
+        let missing = CommandNotifier{command: vec!("yoloswagmannen".to_string())};
+        assert!(missing.notify(Event::StackCreated).is_err());
+    }
+}
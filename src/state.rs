@@ -0,0 +1,98 @@
+use rustc_serialize::{json, Encodable, Decodable};
+
+use std::env;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+
+/**
+  Returns the file that the daemon state is stored in. The runtime dir is
+  preferred since stacks don't outlive the X session, $XDG_STATE_HOME and
+  ~/.local/state are used if it isn't set.
+
+  The file is named after DISPLAY to allow several X sessions at once
+*/
+pub fn state_file_path() -> Option<PathBuf>
+{
+    let directory = env::var("XDG_RUNTIME_DIR")
+        .or(env::var("XDG_STATE_HOME"))
+        .map(PathBuf::from)
+        .or(env::var("HOME").map(|home| PathBuf::from(home).join(".local/state")));
+
+    let display = env::var("DISPLAY").unwrap_or(String::new());
+
+    directory.ok()
+        .map(|directory| directory.join("rspwm").join(format!("stacks{}.json", display)))
+}
+
+/**
+  Writes the state to the specified file, creating the parent directory if needed.
+  Created directories are only accessible by the user
+*/
+pub fn save<T: Encodable>(path: &PathBuf, state: &T) -> Result<(), String>
+{
+    let encoded = json::encode(state).map_err(|e| format!("Failed to encode state: {}", e))?;
+
+    if let Some(parent) = path.parent()
+    {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+
+    //Write to a temporary file first to avoid leaving a half written file behind
+    let temporary_path = path.with_extension("tmp");
+    File::create(&temporary_path)
+        .and_then(|mut file| file.write_all(encoded.as_bytes()))
+        .and_then(|_| fs::rename(&temporary_path, path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/**
+  Reads the state from the specified file. Ok(None) if the file does not exist
+*/
+pub fn load<T: Decodable>(path: &PathBuf) -> Result<Option<T>, String>
+{
+    let mut content = String::new();
+
+    match File::open(path)
+    {
+        Ok(mut file) => {
+            file.read_to_string(&mut content)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        }
+        Err(_) => return Ok(None)
+    }
+
+    json::decode(&content)
+        .map(Some)
+        .map_err(|e| format!("Failed to decode {}: {}", path.display(), e))
+}
+
+
+#[cfg(test)]
+mod state_tests
+{
+    use super::*;
+
+    #[test]
+    fn save_load_test()
+    {
+        let path = env::temp_dir()
+            .join(format!("rspwm-state-test-{}", ::std::process::id()))
+            .join("stacks.json");
+
+        assert_eq!(load::<Vec<u64>>(&path), Ok(None));
+
+        save(&path, &vec!(1u64, 2, 3)).unwrap();
+        assert_eq!(load::<Vec<u64>>(&path), Ok(Some(vec!(1, 2, 3))));
+
+        let permissions = fs::metadata(path.parent().unwrap()).unwrap().permissions();
+        assert_eq!(::std::os::unix::fs::PermissionsExt::mode(&permissions) & 0o777, 0o700);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -4,12 +4,15 @@ use std::process::Command;
 use std::string::String;
 use std::vec::Vec;
 
+use bspwm::BspwmError;
+
 /**
     Calls a system program with the specified arguments as a vector
 
-    Returns the output as UTF8 if successfull, Err(String) if not
+    Returns the output as UTF8 if successfull. Err if the program can't be
+    started, exits with a non-zero status or prints something that isn't UTF8
  */
-pub fn call_program(program_name: &str, args: &Vec<&str>) -> Result<String, String>
+pub fn call_program(program_name: &str, args: &Vec<&str>) -> Result<String, BspwmError>
 {
     let mut cmd = Command::new(&program_name);
     
@@ -18,11 +21,20 @@ pub fn call_program(program_name: &str, args: &Vec<&str>) -> Result<String, Stri
         cmd.arg(&arg);
     }
 
-    match cmd.output()
+    let result = match cmd.output()
+    {
+        Ok(result) => result,
+        Err(e) => return Err(BspwmError::Spawn(format!("Failed to run {}: {}", program_name, e)))
+    };
+
+    if !result.status.success()
     {
-        Ok(result) => Ok(String::from_utf8(result.stdout).unwrap()),
-        Err(_) => Err("failed to run program".to_string())
+        let message = String::from_utf8_lossy(&result.stderr).trim().to_string();
+        return Err(BspwmError::NonZeroExit(message))
     }
+
+    String::from_utf8(result.stdout)
+        .map_err(|_| BspwmError::Unparsable(format!("{} printed invalid UTF8", program_name)))
 }
 
 
@@ -37,5 +49,10 @@ mod subprogram_tests
         assert_eq!(call_program("sh", &vec!("-c", "echo hello")).unwrap(), "hello\n".to_string());
 
         assert!(call_program("yoloswagmannen", &vec!("-c", "echo hello")).is_err());
+
+        assert_eq!(
+                call_program("sh", &vec!("-c", "echo failed >&2; exit 1")),
+                Err(BspwmError::NonZeroExit("failed".to_string()))
+            );
     }
 }
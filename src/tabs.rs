@@ -0,0 +1,211 @@
+use rustc_serialize::json;
+
+use std::env;
+use std::io::prelude::*;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+/**
+  A member of a stack as shown in a status bar
+*/
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Tab
+{
+    pub node: u64,
+    pub class_name: Option<String>,
+    pub instance_name: Option<String>,
+    /// Whether this is the expanded member of the stack
+    pub focused: bool
+}
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct StackTabs
+{
+    pub root: u64,
+    pub tabs: Vec<Tab>
+}
+
+/**
+  All stacks on a monitor
+*/
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct TabBar
+{
+    pub monitor: String,
+    pub stacks: Vec<StackTabs>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TabFormat
+{
+    Lemonbar,
+    Polybar,
+    Plain,
+    Json
+}
+
+impl TabFormat
+{
+    pub fn from_str(string: &str) -> Option<TabFormat>
+    {
+        match string
+        {
+            "lemonbar" => Some(TabFormat::Lemonbar),
+            "polybar" => Some(TabFormat::Polybar),
+            "plain" => Some(TabFormat::Plain),
+            "json" => Some(TabFormat::Json),
+            _ => None
+        }
+    }
+}
+
+impl Tab
+{
+    /**
+      The class name of the window, falling back to the instance name and
+      the node id
+    */
+    pub fn label(&self) -> String
+    {
+        self.class_name.clone()
+            .or(self.instance_name.clone())
+            .unwrap_or(format!("0x{:08X}", self.node))
+    }
+}
+
+/**
+  Formats a tab bar as a single line. Stacks are separated by `|`
+*/
+pub fn format_tab_bar(bar: &TabBar, format: &TabFormat) -> String
+{
+    if *format == TabFormat::Json
+    {
+        return json::encode(bar).unwrap();
+    }
+
+    let format_tab = |tab: &Tab| {
+        //Lemonbar and polybar interpret % as the start of a formatting tag
+        let label = match *format
+        {
+            TabFormat::Plain => tab.label(),
+            _ => tab.label().replace("%", "%%")
+        };
+
+        match (format, tab.focused)
+        {
+            (&TabFormat::Lemonbar, true) => format!("%{{R}} {} %{{R}}", label),
+            (&TabFormat::Polybar, true) => format!("%{{+u}} {} %{{-u}}", label),
+            (&TabFormat::Plain, true) => format!("[{}]", label),
+            _ => format!(" {} ", label)
+        }
+    };
+
+    bar.stacks.iter()
+        .map(|stack| stack.tabs.iter().map(&format_tab).collect::<Vec<_>>().join(""))
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+/**
+  Returns the path of the socket that the tab bars are published on
+*/
+pub fn feed_socket_path() -> Option<PathBuf>
+{
+    let display = env::var("DISPLAY").unwrap_or(String::new());
+
+    env::var("XDG_RUNTIME_DIR").ok()
+        .map(|directory| PathBuf::from(directory).join("rspwm").join(format!("tabs{}.socket", display)))
+}
+
+/**
+  Sends a line to all subscribers. Subscribers that have gone away are removed,
+  and so are the ones that stopped reading since the writes don't block
+*/
+pub fn publish_line(subscribers: &mut Vec<UnixStream>, line: &str)
+{
+    subscribers.retain(|mut subscriber| {
+        subscriber.write_all(format!("{}\n", line).as_bytes()).is_ok()
+    });
+}
+
+/**
+  Adds a subscriber and sends it the current line. The subscriber is made
+  non-blocking so that a subscriber which doesn't read can't block the daemon
+*/
+pub fn add_subscriber(subscribers: &mut Vec<UnixStream>, subscriber: UnixStream, line: &str)
+{
+    if let Err(e) = subscriber.set_nonblocking(true)
+    {
+        println!("Failed to add tab subscriber: {}", e);
+        return
+    }
+
+    let mut new_subscribers = vec!(subscriber);
+    publish_line(&mut new_subscribers, line);
+
+    subscribers.append(&mut new_subscribers);
+}
+
+
+#[cfg(test)]
+mod tabs_tests
+{
+    use super::*;
+
+    fn tab(node: u64, class_name: &str, focused: bool) -> Tab
+    {
+        Tab {
+            node: node,
+            class_name: Some(class_name.to_string()),
+            instance_name: None,
+            focused: focused
+        }
+    }
+
+    #[test]
+    fn format_tab_bar_test()
+    {
+        let bar = TabBar {
+            monitor: "DP-1".to_string(),
+            stacks: vec!(
+                StackTabs {root: 1, tabs: vec!(tab(2, "URxvt", false), tab(3, "100%", true))},
+                StackTabs {root: 4, tabs: vec!(tab(5, "Firefox", true))}
+            )
+        };
+
+        assert_eq!(format_tab_bar(&bar, &TabFormat::Plain), " URxvt [100%]|[Firefox]");
+        assert_eq!(
+                format_tab_bar(&bar, &TabFormat::Lemonbar),
+                " URxvt %{R} 100%% %{R}|%{R} Firefox %{R}"
+            );
+        assert_eq!(
+                format_tab_bar(&bar, &TabFormat::Polybar),
+                " URxvt %{+u} 100%% %{-u}|%{+u} Firefox %{-u}"
+            );
+
+        let decoded: TabBar = json::decode(&format_tab_bar(&bar, &TabFormat::Json)).unwrap();
+        assert_eq!(decoded, bar);
+    }
+
+    #[test]
+    fn stalled_subscriber_test()
+    {
+        let (subscriber, _peer) = UnixStream::pair().unwrap();
+        let mut subscribers = vec!();
+        add_subscriber(&mut subscribers, subscriber, "[]");
+        assert_eq!(subscribers.len(), 1);
+
+        //The peer never reads, so the socket buffer fills up and the
+        //subscriber is dropped instead of blocking
+        let line = "x".repeat(4096);
+        for _ in 0..10000
+        {
+            if subscribers.is_empty()
+            {
+                break
+            }
+            publish_line(&mut subscribers, &line);
+        }
+        assert!(subscribers.is_empty());
+    }
+}
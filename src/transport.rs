@@ -0,0 +1,240 @@
+use rustc_serialize::{json, Encodable, Decodable};
+
+use typed_messages;
+
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/**
+  The port used when communicating over TCP
+*/
+pub const TCP_PORT: u16 = 9232;
+
+/**
+  How rspc and rspwm talk to each other
+*/
+#[derive(Debug, PartialEq)]
+pub enum Transport
+{
+    Unix(PathBuf),
+    Tcp(u16)
+}
+
+impl Transport
+{
+    /**
+      Returns the TCP transport if `tcp` is set, otherwise the UNIX socket
+      for the current display
+    */
+    pub fn select(tcp: bool, tcp_port: u16) -> Result<Transport, String>
+    {
+        match tcp
+        {
+            true => Ok(Transport::Tcp(tcp_port)),
+            false => socket_path()
+                .map(Transport::Unix)
+                .ok_or("XDG_RUNTIME_DIR is not set, use --tcp instead".to_string())
+        }
+    }
+
+    /**
+      Sends a message to the server and waits for the reply
+    */
+    pub fn send<M: Encodable, R: Decodable>(&self, message: M, timeout: Option<Duration>)
+        -> Result<R, String>
+    {
+        match *self
+        {
+            Transport::Unix(ref path) => unix_send_read(path, message, timeout),
+            Transport::Tcp(port) =>
+            {
+                typed_messages::connect_send_read("localhost", port, message, timeout)
+                    .map_err(|e| format!("{:?}", e))
+            }
+        }
+    }
+
+    /**
+      Runs a server which replies to every message with the result of the handler
+    */
+    pub fn serve<M, R, F>(&self, handler: F, timeout: Option<Duration>) -> Result<(), String>
+        where M: Decodable, R: Encodable, F: FnMut(M) -> R
+    {
+        match *self
+        {
+            Transport::Unix(ref path) => unix_read_reply_server(path, handler, timeout),
+            Transport::Tcp(port) =>
+            {
+                typed_messages::run_read_reply_server(port, handler, timeout)
+                    .map_err(|e| format!("{:?}", e))
+            }
+        }
+    }
+}
+
+/**
+  Returns the path of the socket for the current display,
+  `$XDG_RUNTIME_DIR/rspwm/rspwm<DISPLAY>.socket`
+*/
+pub fn socket_path() -> Option<PathBuf>
+{
+    let display = env::var("DISPLAY").unwrap_or(String::new());
+
+    env::var("XDG_RUNTIME_DIR").ok()
+        .map(|directory| PathBuf::from(directory).join("rspwm").join(format!("rspwm{}.socket", display)))
+}
+
+fn unix_send_read<M: Encodable, R: Decodable>(
+        path: &PathBuf,
+        message: M,
+        timeout: Option<Duration>
+    ) -> Result<R, String>
+{
+    let stream = UnixStream::connect(path)
+        .map_err(|e| format!("Failed to connect to {}: {}", path.display(), e))?;
+
+    stream.set_read_timeout(timeout).and_then(|_| stream.set_write_timeout(timeout))
+        .map_err(|e| format!("Failed to set timeout: {}", e))?;
+
+    write_message(&stream, &message)?;
+    read_message(&stream)
+}
+
+/**
+  Binds a UNIX socket that only the current user can access. A stale socket
+  file is removed if no server is listening on it
+*/
+pub fn bind_private_socket(path: &PathBuf) -> Result<UnixListener, String>
+{
+    if let Some(parent) = path.parent()
+    {
+        DirBuilder::new().recursive(true).mode(0o700).create(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+
+    //A socket file is left behind if the previous server crashed
+    if path.exists()
+    {
+        match UnixStream::connect(path)
+        {
+            Ok(_) => return Err(format!("A server is already listening on {}", path.display())),
+            Err(_) => {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    let listener = UnixListener::bind(path)
+        .map_err(|e| format!("Failed to bind {}: {}", path.display(), e))?;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("Failed to set permissions on {}: {}", path.display(), e))?;
+
+    Ok(listener)
+}
+
+fn unix_read_reply_server<M, R, F>(path: &PathBuf, mut handler: F, timeout: Option<Duration>)
+    -> Result<(), String>
+    where M: Decodable, R: Encodable, F: FnMut(M) -> R
+{
+    let listener = bind_private_socket(path)?;
+
+    for stream in listener.incoming()
+    {
+        let stream = match stream
+        {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Failed to accept connection: {}", e);
+                continue
+            }
+        };
+
+        let result = stream.set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+            .map_err(|e| format!("Failed to set timeout: {}", e))
+            .and_then(|_| read_message(&stream))
+            .and_then(|message| write_message(&stream, &handler(message)));
+
+        if let Err(msg) = result
+        {
+            println!("{}", msg);
+        }
+    }
+
+    Ok(())
+}
+
+/**
+  Messages are sent as a single line of json
+*/
+fn write_message<M: Encodable>(mut stream: &UnixStream, message: &M) -> Result<(), String>
+{
+    let encoded = json::encode(message).map_err(|e| format!("Failed to encode message: {}", e))?;
+
+    stream.write_all(format!("{}\n", encoded).as_bytes())
+        .map_err(|e| format!("Failed to send message: {}", e))
+}
+
+fn read_message<M: Decodable>(stream: &UnixStream) -> Result<M, String>
+{
+    let mut line = String::new();
+
+    BufReader::new(stream).read_line(&mut line)
+        .map_err(|e| format!("Failed to read message: {}", e))?;
+
+    json::decode(&line).map_err(|e| format!("Failed to decode message: {}", e))
+}
+
+
+#[cfg(test)]
+mod transport_tests
+{
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn unix_round_trip_test()
+    {
+        let path = env::temp_dir()
+            .join(format!("rspwm-transport-test-{}", ::std::process::id()))
+            .join("rspwm.socket");
+
+        {
+            let path = path.clone();
+            thread::spawn(move || {
+                unix_read_reply_server(&path, |message: Vec<u64>| message.len(), None).unwrap();
+            });
+        }
+
+        //Wait for the server to start listening
+        let timeout = Some(Duration::new(1, 0));
+        let mut reply = Err(String::new());
+        for _ in 0..100
+        {
+            reply = unix_send_read::<_, usize>(&path, vec!(1u64, 2, 3), timeout);
+            if reply.is_ok()
+            {
+                break
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(reply, Ok(3));
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mode = fs::metadata(path.parent().unwrap()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}